@@ -0,0 +1,405 @@
+//! # Library Cache
+//!
+//! Reads and writes the binary `lib.pl` cache that stores the scanned library between runs.
+//!
+//! For a detailed explanation of the functions and their usage, see
+//! [the detailed explanation](../../explanations/cache.md).
+
+
+
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use memmap::Mmap;
+use crate::file::{cleanup_temp, mmap_file, read_i32_le, read_u32_le, read_u64_le, write_atomic};
+
+
+
+
+const MAGIC: &[u8; 4] = b"CTC\0";
+const VERSION: u32 = 2;
+const HEADER_SIZE: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedTrack {
+    pub path: PathBuf,
+    pub mtime: i64,
+    pub duration: i32, // Seconds, -1 if unknown
+    pub play_count: u32,
+    pub tags: Vec<(String, String)>,
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Walks the fields of one record body
+struct Fields<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let value = read_u32_le(self.data, self.pos)?;
+        self.pos += 4;
+        Some(value)
+    }
+
+    fn i32(&mut self) -> Option<i32> {
+        let value = read_i32_le(self.data, self.pos)?;
+        self.pos += 4;
+        Some(value)
+    }
+
+    fn i64(&mut self) -> Option<i64> {
+        let value = read_u64_le(self.data, self.pos)? as i64;
+        self.pos += 8;
+        Some(value)
+    }
+
+    fn string(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.bytes(len)
+    }
+
+    fn utf8(&mut self) -> Option<String> {
+        String::from_utf8(self.string()?.to_vec()).ok()
+    }
+}
+
+fn parse_record(body: &[u8], version: u32) -> Option<CachedTrack> {
+    let mut fields = Fields { data: body, pos: 0 };
+    let path = PathBuf::from(OsStr::from_bytes(fields.string()?));
+    let mtime = fields.i64()?;
+    let duration = fields.i32()?;
+    // Version 1 had no play count
+    let play_count = if version >= 2 { fields.u32()? } else { 0 };
+
+    let count = fields.u32()?;
+    let mut tags = Vec::new();
+    for _ in 0..count {
+        tags.push((fields.utf8()?, fields.utf8()?));
+    }
+
+    Some(CachedTrack { path, mtime, duration, play_count, tags })
+}
+
+// Checks the magic and returns the mapped file and its format version
+fn open_cache(path: &Path) -> io::Result<(Mmap, usize, u32)> {
+    // The cache is saved with write_atomic; drop what a crash mid-save left behind
+    cleanup_temp(path)?;
+    if std::fs::metadata(path)?.len() < HEADER_SIZE as u64 {
+        return Err(invalid_data("library cache is too short"));
+    }
+    let (data, size) = unsafe { mmap_file(path)? };
+
+    if &data[..4] != MAGIC {
+        return Err(invalid_data("not a library cache"));
+    }
+    let version = read_u32_le(&data, 4).unwrap();
+    Ok((data, size, version))
+}
+
+// Returns the records and whether the file ended part way through one
+fn parse_records(data: &[u8], version: u32, lenient: bool) -> io::Result<(Vec<CachedTrack>, bool)> {
+    // Each record is a u32 length followed by that many bytes of fields
+    let mut tracks = Vec::new();
+    let mut pos = HEADER_SIZE;
+    while pos < data.len() {
+        let body = match read_u32_le(data, pos) {
+            Some(len) => {
+                // The length comes from the file, so the end offset mustn't wrap around
+                let start = pos + 4;
+                let end = start.checked_add(len as usize)
+                    .ok_or_else(|| invalid_data("library cache record length overflows"))?;
+                data.get(start..end)
+            }
+            None => None,
+        };
+        let Some(body) = body else {
+            if lenient {
+                return Ok((tracks, true));
+            }
+            return Err(invalid_data("truncated library cache record"));
+        };
+        tracks.push(parse_record(body, version).ok_or_else(|| invalid_data("malformed library cache record"))?);
+        pos += 4 + body.len();
+    }
+
+    Ok((tracks, false))
+}
+
+fn newer_version(version: u32) -> io::Error {
+    invalid_data(&format!(
+        "library cache version {} is newer than the supported version {}", version, VERSION
+    ))
+}
+
+fn read_current(path: &Path, lenient: bool) -> io::Result<(Vec<CachedTrack>, bool)> {
+    let (data, size, version) = open_cache(path)?;
+    if version > VERSION {
+        return Err(newer_version(version));
+    }
+    if version != VERSION {
+        return Err(invalid_data(&format!(
+            "library cache version {} is older than version {} and needs migrating", version, VERSION
+        )));
+    }
+    parse_records(&data[..size], version, lenient)
+}
+
+pub fn read_lib_cache(path: &Path) -> io::Result<Vec<CachedTrack>> {
+    Ok(read_current(path, false)?.0)
+}
+
+pub fn read_lib_cache_lenient(path: &Path) -> io::Result<(Vec<CachedTrack>, bool)> {
+    read_current(path, true)
+}
+
+
+// Lengths are stored as u32; anything longer would read back as garbage
+fn len_u32(len: usize) -> io::Result<u32> {
+    u32::try_from(len).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too large for the library cache"))
+}
+
+fn put_string(data: &mut Vec<u8>, bytes: &[u8]) -> io::Result<()> {
+    data.extend_from_slice(&len_u32(bytes.len())?.to_le_bytes());
+    data.extend_from_slice(bytes);
+    Ok(())
+}
+
+fn encode_record(track: &CachedTrack, data: &mut Vec<u8>) -> io::Result<()> {
+    let mut body = Vec::new();
+    put_string(&mut body, track.path.as_os_str().as_bytes())?;
+    body.extend_from_slice(&track.mtime.to_le_bytes());
+    body.extend_from_slice(&track.duration.to_le_bytes());
+    body.extend_from_slice(&track.play_count.to_le_bytes());
+    body.extend_from_slice(&len_u32(track.tags.len())?.to_le_bytes());
+    for (key, value) in &track.tags {
+        put_string(&mut body, key.as_bytes())?;
+        put_string(&mut body, value.as_bytes())?;
+    }
+
+    data.extend_from_slice(&len_u32(body.len())?.to_le_bytes());
+    data.extend_from_slice(&body);
+    Ok(())
+}
+
+pub fn write_lib_cache(path: &Path, tracks: &[CachedTrack]) -> io::Result<()> {
+    let mut data = MAGIC.to_vec();
+    data.extend_from_slice(&VERSION.to_le_bytes());
+    for track in tracks {
+        encode_record(track, &mut data)?;
+    }
+    write_atomic(path, &data)
+}
+
+
+pub fn migrate_cache(path: &Path) -> io::Result<bool> {
+    let (data, size, version) = open_cache(path)?;
+    if version > VERSION {
+        return Err(newer_version(version));
+    }
+    if version == VERSION {
+        return Ok(false);
+    }
+
+    // parse_record fills in the fields older versions lack
+    let (tracks, _) = parse_records(&data[..size], version, false)?;
+    drop(data); // Unmap before the file is replaced
+    write_lib_cache(path, &tracks)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{remove_file, write};
+
+    // A play count of None gives a version 1 record
+    fn record(path: &str, mtime: i64, duration: i32, play_count: Option<u32>, tags: &[(&str, &str)]) -> Vec<u8> {
+        let mut body = Vec::new();
+        put_string(&mut body, path.as_bytes()).unwrap();
+        body.extend_from_slice(&mtime.to_le_bytes());
+        body.extend_from_slice(&duration.to_le_bytes());
+        if let Some(play_count) = play_count {
+            body.extend_from_slice(&play_count.to_le_bytes());
+        }
+        body.extend_from_slice(&(tags.len() as u32).to_le_bytes());
+        for (key, value) in tags {
+            put_string(&mut body, key.as_bytes()).unwrap();
+            put_string(&mut body, value.as_bytes()).unwrap();
+        }
+
+        let mut data = (body.len() as u32).to_le_bytes().to_vec();
+        data.extend_from_slice(&body);
+        data
+    }
+
+    fn cache_fixture(version: u32, records: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&version.to_le_bytes());
+        data.extend_from_slice(&records.concat());
+        data
+    }
+
+    #[test]
+    fn test_read_lib_cache() {
+        let path = Path::new("testfile_read_lib_cache.pl");
+        write(path, cache_fixture(VERSION, &[
+            record("/music/Kind of Blue/01 So What.flac", 1_700_000_000, 562, Some(12), &[("title", "So What"), ("artist", "Miles Davis")]),
+            record("/music/unknown.mp3", -1, -1, Some(0), &[]),
+        ])).unwrap();
+
+        let tracks = read_lib_cache(path).unwrap();
+        assert_eq!(tracks, vec![
+            CachedTrack {
+                path: PathBuf::from("/music/Kind of Blue/01 So What.flac"),
+                mtime: 1_700_000_000,
+                duration: 562,
+                play_count: 12,
+                tags: vec![("title".to_string(), "So What".to_string()), ("artist".to_string(), "Miles Davis".to_string())],
+            },
+            CachedTrack { path: PathBuf::from("/music/unknown.mp3"), mtime: -1, duration: -1, play_count: 0, tags: vec![] },
+        ]);
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_lib_cache_bad_header() {
+        let path = Path::new("testfile_read_lib_cache_bad_header.pl");
+        write(path, b"NOPE\x01\0\0\0").unwrap();
+        assert_eq!(read_lib_cache(path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        write(path, cache_fixture(VERSION + 1, &[])).unwrap();
+        assert_eq!(read_lib_cache(path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_write_lib_cache_round_trip() {
+        let path = Path::new("testfile_write_lib_cache_round_trip.pl");
+        let track = |path: &str, duration: i32, tags: &[(&str, &str)]| CachedTrack {
+            path: PathBuf::from(path),
+            mtime: 1_650_000_000 + duration as i64,
+            duration,
+            play_count: duration.max(0) as u32 / 10,
+            tags: tags.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+        };
+        let tracks = vec![
+            track("/music/Sigur Rós/Ágætis byrjun/01 Intro.flac", 96, &[("title", "Intro"), ("artist", "Sigur Rós")]),
+            track("/music/坂本龍一/戦場のメリークリスマス.mp3", 281, &[("title", "戦場のメリークリスマス")]),
+            track("/music/untagged.ogg", -1, &[]),
+        ];
+
+        write_lib_cache(path, &tracks).unwrap();
+        assert_eq!(read_lib_cache(path).unwrap(), tracks);
+
+        write_lib_cache(path, &[]).unwrap();
+        assert_eq!(read_lib_cache(path).unwrap(), vec![]);
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_cache() {
+        let path = Path::new("testfile_migrate_cache.pl");
+        write(path, cache_fixture(1, &[
+            record("/music/a.flac", 1_600_000_000, 200, None, &[("title", "A")]),
+            record("/music/b.flac", 1_600_000_001, 300, None, &[]),
+        ])).unwrap();
+        assert_eq!(read_lib_cache(path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        assert!(migrate_cache(path).unwrap());
+        let tracks = read_lib_cache(path).unwrap();
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0].path, PathBuf::from("/music/a.flac"));
+        assert_eq!(tracks[0].tags, vec![("title".to_string(), "A".to_string())]);
+        assert_eq!(tracks[1].duration, 300);
+        assert!(tracks.iter().all(|track| track.play_count == 0));
+
+        // Already current
+        assert!(!migrate_cache(path).unwrap());
+
+        write(path, cache_fixture(VERSION + 1, &[])).unwrap();
+        assert_eq!(migrate_cache(path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_lib_cache_lenient() {
+        let path = Path::new("testfile_read_lib_cache_lenient.pl");
+        let mut data = cache_fixture(VERSION, &[
+            record("/music/a.flac", 1, 100, Some(1), &[("title", "A")]),
+            record("/music/b.flac", 2, 200, Some(2), &[("title", "B")]),
+            record("/music/c.flac", 3, 300, Some(3), &[("title", "C")]),
+        ]);
+        data.truncate(data.len() - 5); // A crash in the middle of the last record
+        write(path, &data).unwrap();
+
+        assert_eq!(read_lib_cache(path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let (tracks, truncated) = read_lib_cache_lenient(path).unwrap();
+        assert!(truncated);
+        assert_eq!(tracks.iter().map(|track| track.duration).collect::<Vec<_>>(), vec![100, 200]);
+
+        // Cut inside the length prefix of a record
+        data.truncate(HEADER_SIZE + 2);
+        write(path, &data).unwrap();
+        assert_eq!(read_lib_cache_lenient(path).unwrap(), (vec![], true));
+
+        write(path, cache_fixture(VERSION, &[record("/music/a.flac", 1, 100, Some(1), &[])])).unwrap();
+        assert!(!read_lib_cache_lenient(path).unwrap().1);
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_lib_cache_removes_stale_temp() {
+        let path = Path::new("testfile_read_lib_cache_stale_temp.pl");
+        let tmp = Path::new("testfile_read_lib_cache_stale_temp.pl.tmp");
+        write(path, cache_fixture(VERSION, &[record("/music/a.flac", 1, 100, Some(1), &[])])).unwrap();
+        write(tmp, b"CTC\0 half-written").unwrap();
+        let old = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+        std::fs::File::options().write(true).open(tmp).unwrap().set_modified(old).unwrap();
+
+        assert_eq!(read_lib_cache(path).unwrap().len(), 1);
+        assert!(!tmp.exists());
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_lib_cache_oversized_length() {
+        let path = Path::new("testfile_read_lib_cache_oversized_length.pl");
+        let mut data = cache_fixture(VERSION, &[record("/music/a.flac", 1, 100, Some(1), &[])]);
+        data.extend_from_slice(&u32::MAX.to_le_bytes()); // A length far past the end of the file
+        data.extend_from_slice(b"short body");
+        write(path, &data).unwrap();
+
+        assert_eq!(read_lib_cache(path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let (tracks, truncated) = read_lib_cache_lenient(path).unwrap();
+        assert_eq!((tracks.len(), truncated), (1, true));
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_write_lib_cache_length_limit() {
+        // Building a 4 GiB string isn't practical, so check the length conversion directly
+        assert_eq!(len_u32(u32::MAX as usize).unwrap(), u32::MAX);
+        if let Some(too_long) = (u32::MAX as usize).checked_add(1) {
+            assert_eq!(len_u32(too_long).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+}
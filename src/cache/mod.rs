@@ -0,0 +1,8 @@
+//! # Cache
+//!
+//! Readers and writers for the player's on-disk caches.
+//!
+//! For a detailed explanation of the functions and their usage, see
+//! [the detailed explanation](../../explanations/cache.md).
+
+pub mod lib_pl;
@@ -0,0 +1,239 @@
+//! # Documents
+//!
+//! An in-memory text file with random access to its lines, for editing
+//! config files in place.
+//!
+//! For a detailed explanation of the functions and their usage, see
+//! [the detailed explanation](../explanations/document.md).
+
+
+
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+use crate::file::{cleanup_temp, write_atomic, Newline};
+
+
+
+
+// Byte offset of the start of each line, plus one past the end of the buffer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineIndex {
+    starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub fn new(buf: &[u8]) -> Self {
+        let mut starts = vec![0];
+        starts.extend(buf.iter().enumerate().filter(|(_, &b)| b == b'\n').map(|(i, _)| i + 1));
+        LineIndex { starts }
+    }
+
+    pub fn len(&self) -> usize {
+        self.starts.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // The range of line `n`, without its newline
+    pub fn line_range(&self, n: usize) -> Option<Range<usize>> {
+        if n >= self.len() {
+            return None;
+        }
+        Some(self.starts[n]..self.starts[n + 1] - 1)
+    }
+
+    fn shift_from(&mut self, n: usize, delta: isize) {
+        for start in &mut self.starts[n..] {
+            *start = start.wrapping_add_signed(delta);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Document {
+    // Every line, including the last, ends with a newline
+    buf: Vec<u8>,
+    index: LineIndex,
+    // Whether the file itself ended with a newline, restored by save
+    final_newline: bool,
+    // The ending of the first line, used for inserted lines
+    newline: Newline,
+}
+
+fn invalid_input(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn check_line(text: &str) -> io::Result<()> {
+    if text.contains('\n') {
+        return Err(invalid_input("line contains a newline"));
+    }
+    Ok(())
+}
+
+impl Document {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        cleanup_temp(path)?;
+        let mut buf = std::fs::read(path)?;
+        if std::str::from_utf8(&buf).is_err() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "document is not valid UTF-8"));
+        }
+        let newline = match buf.iter().position(|&b| b == b'\n') {
+            Some(i) if i > 0 && buf[i - 1] == b'\r' => Newline::CrLf,
+            _ => Newline::Lf,
+        };
+        let final_newline = buf.is_empty() || buf.ends_with(b"\n");
+        if !final_newline {
+            buf.extend_from_slice(newline.as_bytes());
+        }
+        let index = LineIndex::new(&buf);
+        Ok(Document { buf, index, final_newline, newline })
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    pub fn line(&self, n: usize) -> Option<&str> {
+        let line = &self.buf[self.index.line_range(n)?];
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        // Only valid UTF-8 is ever stored, and lines are split on ASCII
+        Some(std::str::from_utf8(line).unwrap())
+    }
+
+    pub fn set_line(&mut self, n: usize, text: &str) -> io::Result<()> {
+        check_line(text)?;
+        let mut range = self.index.line_range(n).ok_or_else(|| invalid_input("line out of range"))?;
+        if self.buf[range.clone()].ends_with(b"\r") {
+            range.end -= 1; // Keep the line's CRLF ending
+        }
+
+        let delta = text.len() as isize - range.len() as isize;
+        self.buf.splice(range, text.bytes());
+        self.index.shift_from(n + 1, delta);
+        Ok(())
+    }
+
+    pub fn insert_line(&mut self, n: usize, text: &str) -> io::Result<()> {
+        check_line(text)?;
+        if n > self.len() {
+            return Err(invalid_input("line out of range"));
+        }
+
+        let start = self.index.starts[n];
+        let ending = self.newline.as_bytes();
+        self.buf.splice(start..start, text.bytes().chain(ending.iter().copied()));
+        self.index.starts.insert(n, start);
+        self.index.shift_from(n + 1, (text.len() + ending.len()) as isize);
+        Ok(())
+    }
+
+    pub fn remove_line(&mut self, n: usize) -> io::Result<()> {
+        if n >= self.len() {
+            return Err(invalid_input("line out of range"));
+        }
+
+        let range = self.index.starts[n]..self.index.starts[n + 1];
+        let delta = -(range.len() as isize);
+        self.buf.drain(range);
+        self.index.starts.remove(n);
+        self.index.shift_from(n, delta);
+        Ok(())
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if self.final_newline {
+            return write_atomic(path, &self.buf);
+        }
+        // The last line goes out without the ending load added to it
+        let mut end = self.buf.strip_suffix(b"\n").unwrap_or(&self.buf);
+        if self.newline == Newline::CrLf {
+            end = end.strip_suffix(b"\r").unwrap_or(end);
+        }
+        write_atomic(path, end)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{remove_file, write};
+
+    fn lines(doc: &Document) -> Vec<&str> {
+        (0..doc.len()).map(|n| doc.line(n).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_document_edits() {
+        let path = Path::new("testfile_document_edits.conf");
+        write(path, "set softvol=true\r\nbind common q quit\nset color_bg=default").unwrap();
+        let mut doc = Document::load(path).unwrap();
+        assert_eq!(lines(&doc), vec!["set softvol=true", "bind common q quit", "set color_bg=default"]);
+
+        doc.set_line(0, "set softvol=false").unwrap();
+        doc.insert_line(1, "# key bindings").unwrap();
+        doc.remove_line(3).unwrap();
+        doc.insert_line(3, "set repeat=true").unwrap();
+        assert_eq!(lines(&doc), vec!["set softvol=false", "# key bindings", "bind common q quit", "set repeat=true"]);
+        assert_eq!(doc.index, LineIndex::new(&doc.buf));
+        // The first line sets the ending for inserted lines
+        assert!(doc.buf.starts_with(b"set softvol=false\r\n# key bindings\r\nbind common q quit\n"));
+
+        assert_eq!(doc.line(4), None);
+        assert_eq!(doc.set_line(4, "x").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(doc.insert_line(0, "a\nb").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(doc.remove_line(4).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_document_save_round_trip() {
+        let path = Path::new("testfile_document_save_round_trip.conf");
+        write(path, "a\nb\n").unwrap();
+        let mut doc = Document::load(path).unwrap();
+        doc.set_line(1, "second").unwrap();
+        doc.insert_line(2, "third").unwrap();
+        doc.save(path).unwrap();
+
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "a\nsecond\nthird\n");
+        let reloaded = Document::load(path).unwrap();
+        assert_eq!(reloaded, doc);
+
+        // A file without a final newline is saved without one
+        write(path, "a\nb").unwrap();
+        Document::load(path).unwrap().save(path).unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"a\nb");
+
+        write(path, "a\r\nb").unwrap();
+        let mut doc = Document::load(path).unwrap();
+        doc.insert_line(2, "c").unwrap();
+        doc.save(path).unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"a\r\nb\r\nc");
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_document_load_removes_stale_temp() {
+        let path = Path::new("testfile_document_stale_temp.conf");
+        let tmp = Path::new("testfile_document_stale_temp.conf.tmp");
+        write(path, "set softvol=true\n").unwrap();
+        write(tmp, "set softvol=fa").unwrap();
+        let old = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+        std::fs::File::options().write(true).open(tmp).unwrap().set_modified(old).unwrap();
+
+        assert_eq!(Document::load(path).unwrap().line(0), Some("set softvol=true"));
+        assert!(!tmp.exists());
+
+        remove_file(path).unwrap();
+    }
+}
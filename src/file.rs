@@ -11,18 +11,25 @@
 
 
 
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::io::{BufRead,Read, Seek, SeekFrom, Write};
 use std::fs::File;
 use std::io;
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, Range};
 use std::os::fd::{FromRawFd, RawFd};
-use std::path::Path;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
 use memmap::{Mmap, MmapOptions};
 
 
 
 
 pub fn read_all(fd: RawFd, buf: &mut [u8]) -> io::Result<usize> {
-    let mut file = unsafe { File::from_raw_fd(fd) };
+    // The caller keeps ownership of `fd`, so never close it here.
+    let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
     let metadata = file.metadata()?;
     if metadata.len() == 0 || buf.is_empty() {
         println!("File is empty or buffer is empty");
@@ -50,6 +57,10 @@ pub fn write_all(mut file: &File, buf: &[u8]) -> io::Result<usize> {
 }
 
 
+/// # Safety
+///
+/// The file must not be truncated or modified by another process while the
+/// returned map is alive.
 pub unsafe fn mmap_file(filename: &Path) -> Result<(Mmap, usize), std::io::Error> {
     let file = File::open(filename)?;
     let metadata = file.metadata()?;
@@ -59,55 +70,67 @@ pub unsafe fn mmap_file(filename: &Path) -> Result<(Mmap, usize), std::io::Error
         let mmap = MmapOptions::new().map(&file)?;
         Ok((mmap, file_size))
     } else {
-        Err(std::io::Error::new(std::io::ErrorKind::Other, "File is empty or does not exist."))
+        Err(std::io::Error::other("File is empty or does not exist."))
     }
 }
 
-pub fn buffer_for_each_line<F>(buf: &[u8], mut cb: F)
+pub fn buffer_for_each_field<'a, F>(buf: &'a [u8], delim: u8, mut cb: F)
 where
-    F: FnMut(&str) -> bool,
+    F: FnMut(&'a [u8]) -> bool,
 {
     let mut pos = 0;
     let size = buf.len();
 
     while pos < size {
         let mut end = pos;
-        while end < size && buf[end] != b'\n' {
+        while end < size && buf[end] != delim {
             end += 1;
         }
-        let mut len = end - pos;
-        if end > pos && buf[end - 1] == b'\r' {
-            len -= 1;
-        }
 
-        let line = &buf[pos..pos + len];
+        let field = &buf[pos..end];
         pos = end + 1;
 
-        if(cb(std::str::from_utf8(line).unwrap())) {
+        if cb(field) {
             break;
         }
-
     }
+}
+
+// Invalid UTF-8 becomes U+FFFD; use buffer_for_each_line_checked where that isn't acceptable
+pub(crate) fn bytes_to_str_lossy(bytes: &[u8]) -> Cow<'_, str> {
+    String::from_utf8_lossy(bytes)
+}
 
+fn strip_cr(line: &[u8]) -> &[u8] {
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+// The line splitting behind buffer_for_each_line, for callers that keep lines borrowed from buf
+fn for_each_line_cow<'a, F>(buf: &'a [u8], mut cb: F)
+where
+    F: FnMut(Cow<'a, str>) -> bool,
+{
+    buffer_for_each_field(buf, b'\n', |line| cb(bytes_to_str_lossy(strip_cr(line))));
+}
+
+pub fn buffer_for_each_line<F>(buf: &[u8], mut cb: F)
+where
+    F: FnMut(&str) -> bool,
+{
+    for_each_line_cow(buf, |line| cb(&line));
 }
 
 pub fn buffer_for_each_line_reverse<F>(buf: &[u8], mut cb: F)
     where
         F: FnMut(&str) -> bool,
 {
-    let mut end = buf.len();
-    if end > 0 {
-        end -= 1;
-    }
+    let mut end = buf.len().saturating_sub(1);
 
     while end > 0 {
-        let mut is_newline = false;
         if end > 1 && buf[end] == b'\n' && buf[end - 1] == b'\r' {
             end -= 2; // Exclude both '\n' and '\r'
-            is_newline = true;
         } else if buf[end] == b'\n' {
             end -= 1; // Exclude '\n'
-            is_newline = true;
         }
 
         let mut pos = end;
@@ -123,151 +146,2829 @@ pub fn buffer_for_each_line_reverse<F>(buf: &[u8], mut cb: F)
             end = 0;
         }
 
-        if cb(std::str::from_utf8(line).unwrap()) {
+        if cb(&bytes_to_str_lossy(line)) {
             break;
         }
     }
 }
 
-pub fn file_for_each_line<F>(filename: &str, mut cb: F) -> io::Result<()>
+pub fn file_for_each_line<F>(filename: &str, cb: F) -> io::Result<()>
     where
         F: FnMut(&str) -> io::Result<()>,
 {
-    let file = File::open(&Path::new(filename))?;
-    let reader = io::BufReader::new(file);
+    let file = File::open(Path::new(filename))?;
+    reader_for_each_line(io::BufReader::new(file), cb)
+}
 
-    for line in reader.lines() {
-        let line = line?;
-        cb(&line)?;
+// Reads the next line into `line` without its ending; false at end of input
+fn read_line_stripped<R: BufRead>(reader: &mut R, line: &mut String) -> io::Result<bool> {
+    line.clear();
+    // A signal (SIGWINCH in the TUI, say) must not abort a library load
+    let n = loop {
+        match reader.read_line(line) {
+            Ok(n) => break n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    };
+    if n == 0 {
+        return Ok(false);
     }
 
+    // Same line endings as BufRead::lines()
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+    Ok(true)
+}
+
+fn reader_for_each_line<R, F>(mut reader: R, mut cb: F) -> io::Result<()>
+    where
+        R: BufRead,
+        F: FnMut(&str) -> io::Result<()>,
+{
+    let mut line = String::new();
+    while read_line_stripped(&mut reader, &mut line)? {
+        cb(&line)?;
+    }
     Ok(())
 }
 
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs::{OpenOptions, remove_file};
-    use std::os::unix::io::AsRawFd;
+pub fn for_each_logical_line<F>(buf: &[u8], mut cb: F)
+where
+    F: FnMut(&str) -> bool,
+{
+    let mut logical = String::new();
+    let mut pending = false;
 
-    #[test]
-    fn test_read_all() {
-        let mut file = OpenOptions::new().write(true).read(true).create(true).open("testfile.txt").unwrap();
-        file.write_all(b"Hello, world!").unwrap();
-        file.sync_all().unwrap(); // Ensure all writes are flushed to the file
-        let fd = file.as_raw_fd();
-        let mut buffer = [0u8; 13];
-        let result = read_all(fd, &mut buffer);
-        let bytes_read = result.unwrap();
-        println!("Read {} bytes", bytes_read ); // Print the number of bytes read
-        println!("Buffer contents: {:?}", &buffer); // Print the contents of the buffer
-        assert_eq!(bytes_read, 13);
-        assert_eq!(&buffer, b"Hello, world!");
+    buffer_for_each_line(buf, |line| {
+        // An odd number of trailing backslashes means the last one is unescaped
+        let trailing = line.bytes().rev().take_while(|&b| b == b'\\').count();
+        if trailing % 2 == 1 {
+            logical.push_str(&line[..line.len() - 1]);
+            pending = true;
+            return false;
+        }
 
-        remove_file("testfile.txt").unwrap();
+        logical.push_str(line);
+        pending = false;
+        let stop = cb(&logical);
+        logical.clear();
+        stop
+    });
+
+    // The buffer ended in the middle of a continuation
+    if pending {
+        cb(&logical);
     }
+}
 
-    #[test]
-    fn test_write_all() {
-        let file = OpenOptions::new().write(true).read(true).create(true).open("testfile.txt").unwrap();
-        let result = write_all(&file, b"Hello, world!");
-        assert_eq!(result.unwrap(), 13);
+pub enum FileData {
+    Owned(Vec<u8>),
+    Mapped(Mmap),
+}
+
+impl Deref for FileData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            FileData::Owned(buf) => buf,
+            FileData::Mapped(mmap) => mmap,
+        }
     }
+}
 
-    #[test]
-    fn test_mmap_file() {
-        let result = unsafe { mmap_file(Path::new("testfile.txt")) };
-        assert!(result.is_ok());
+pub fn read_file_smart(path: &Path, mmap_threshold: usize) -> io::Result<FileData> {
+    let size = std::fs::metadata(path)?.len();
+    if size > mmap_threshold as u64 {
+        // Same caveat as mmap_file: the file must not shrink while mapped
+        let (mmap, _) = unsafe { mmap_file(path)? };
+        Ok(FileData::Mapped(mmap))
+    } else {
+        Ok(FileData::Owned(std::fs::read(path)?))
     }
+}
 
-    #[test]
-    fn test_buffer_for_each_line() {
-        let buffer = b"Hello\nworld\n";
-        let mut lines = Vec::new();
-        buffer_for_each_line(buffer, |line| {
-            lines.push(line.to_string());
-            false
-        });
-        assert_eq!(lines, vec!["Hello", "world"]);
+pub fn read_first_line(path: &Path) -> io::Result<Option<String>> {
+    let file = File::open(path)?;
+    let mut reader = io::BufReader::new(file);
+    let mut line = String::new();
+
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.ends_with('\n') {
+        line.pop();
+    }
+    if line.ends_with('\r') {
+        line.pop();
     }
 
-    #[test]
-    fn test_buffer_for_each_line_reverse() {
-        let buffer = b"Hello\nworld\n";
-        let mut lines = Vec::new();
-        buffer_for_each_line_reverse(buffer, |line| {
-            lines.push(line.to_string());
-            false
-        });
-        assert_eq!(lines, vec!["world", "Hello"]);
+    Ok(Some(line))
+}
+
+pub fn buffer_for_each_trimmed_line<F>(buf: &[u8], mut cb: F)
+where
+    F: FnMut(&str) -> bool,
+{
+    buffer_for_each_line(buf, |line| cb(line.trim_matches(|c: char| c.is_ascii_whitespace())));
+}
+
+pub fn for_each_line_with_pos<F>(filename: &str, mut cb: F) -> io::Result<()>
+    where
+        F: FnMut(&str, u64) -> io::Result<()>,
+{
+    let file = File::open(Path::new(filename))?;
+    let mut reader = io::BufReader::new(file);
+    let mut line = String::new();
+    let mut pos = 0u64;
+
+    loop {
+        line.clear();
+        let n = reader.read_line(&mut line)?;
+        if n == 0 {
+            break;
+        }
+        pos += n as u64; // Includes the consumed newline
+
+        let text = line.strip_suffix('\n').unwrap_or(&line);
+        let text = text.strip_suffix('\r').unwrap_or(text);
+        cb(text, pos)?;
     }
 
-    #[test]
-    fn test_file_for_each_line() {
-        let result = file_for_each_line("testfile.txt", |line| {
-            assert!(line.len() > 0);
-            Ok(())
-        });
-        assert!(result.is_ok());
+    Ok(())
+}
+
+pub fn read_some(file: &mut File, buf: &mut [u8]) -> io::Result<(usize, bool)> {
+    let mut pos = 0;
+
+    while pos < buf.len() {
+        match file.read(&mut buf[pos..]) {
+            Ok(0) => return Ok((pos, true)), // EOF
+            Ok(n) => pos += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
     }
 
+    Ok((pos, false))
+}
 
-    #[test]
-    fn test_read_all_empty_file() {
-        let path = Path::new("empty.txt");
-        if !path.exists() {
-            File::create(&path).expect("Failed to create file 'empty.txt'");
+pub fn buffer_for_each_line_checked<F>(buf: &[u8], mut cb: F)
+where
+    F: FnMut(Result<&str, &[u8]>) -> bool,
+{
+    buffer_for_each_field(buf, b'\n', |line| {
+        let line = strip_cr(line);
+        cb(std::str::from_utf8(line).map_err(|_| line))
+    });
+}
+
+pub(crate) fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)),
+        None => false,
+    }
+}
+
+pub fn scan_dir_report<F>(root: &Path, extensions: &[&str], on_found: F, skipped: Option<&mut Vec<PathBuf>>) -> io::Result<()>
+    where
+        F: FnMut(&Path),
+{
+    scan_dir_with(root, extensions, on_found, skipped, |dir| std::fs::read_dir(dir))
+}
+
+// scan_dir_report with the directory listing passed in, so tests can make it fail
+fn scan_dir_with<F, R>(root: &Path, extensions: &[&str], mut on_found: F, mut skipped: Option<&mut Vec<PathBuf>>, read_dir: R) -> io::Result<()>
+    where
+        F: FnMut(&Path),
+        R: Fn(&Path) -> io::Result<std::fs::ReadDir>,
+{
+    fn walk<F, R>(dir: &Path, extensions: &[&str], on_found: &mut F, skipped: &mut Option<&mut Vec<PathBuf>>, read_dir: &R) -> io::Result<()>
+        where
+            F: FnMut(&Path),
+            R: Fn(&Path) -> io::Result<std::fs::ReadDir>,
+    {
+        let mut entries = read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let path = entry.path();
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                match walk(&path, extensions, on_found, skipped, read_dir) {
+                    Ok(()) => {},
+                    // Unreadable subdirectories are skipped so the rest of the library still loads
+                    Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                        if let Some(skipped) = skipped.as_mut() {
+                            skipped.push(path);
+                        }
+                    }
+                    // Deleted since its parent was listed, so there is nothing left to find
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                    Err(e) => return Err(e),
+                }
+            } else if (file_type.is_file() || path.is_file()) && has_extension(&path, extensions) {
+                // Symlinked files are reported, but symlinked directories are not followed
+                on_found(&path);
+            }
         }
-        let file = File::open(&path).expect("Failed to open file 'empty.txt'");
-        let fd = file.as_raw_fd();
-        let mut buffer = [0u8; 10];
-        let result = read_all(fd, &mut buffer);
-        assert_eq!(result.unwrap(), 0);
-        remove_file(path).unwrap()
+
+        Ok(())
     }
 
-    #[test]
-    fn test_write_all_empty_buffer() {
-        let file = OpenOptions::new().write(true).read(true).create(true).open("testfile.txt").unwrap();
-        let result = write_all(&file, b"");
-        assert_eq!(result.unwrap(), 0);
+    walk(root, extensions, &mut on_found, &mut skipped, &read_dir)
+}
+
+pub fn scan_dir_progress<F>(root: &Path, extensions: &[&str], on_found: F) -> io::Result<()>
+    where
+        F: FnMut(&Path),
+{
+    scan_dir_report(root, extensions, on_found, None)
+}
+
+pub fn scan_dir(root: &Path, extensions: &[&str]) -> io::Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    scan_dir_progress(root, extensions, |path| found.push(path.to_path_buf()))?;
+    Ok(found)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Newline {
+    Lf,
+    CrLf,
+}
+
+impl Newline {
+    pub fn as_bytes(self) -> &'static [u8] {
+        match self {
+            Newline::Lf => b"\n",
+            Newline::CrLf => b"\r\n",
+        }
     }
+}
 
-    #[test]
-    fn test_mmap_file_non_existent() {
-        let result = unsafe { mmap_file(Path::new("non_existent.txt")) };
-        assert!(result.is_err());
+pub fn collapse_blank_lines(buf: &[u8], newline: Newline) -> Vec<u8> {
+    let mut out = Vec::with_capacity(buf.len());
+    let mut run: Vec<&[u8]> = Vec::new();
+
+    let flush = |run: &mut Vec<&[u8]>, out: &mut Vec<u8>| {
+        match run.len() {
+            0 => {}
+            1 => out.extend_from_slice(run[0]), // A lone blank line is kept as-is
+            _ => out.extend_from_slice(newline.as_bytes()),
+        }
+        run.clear();
+    };
+
+    // Each segment keeps its own line ending
+    for segment in buf.split_inclusive(|&b| b == b'\n') {
+        if segment.iter().all(|b| b.is_ascii_whitespace()) {
+            run.push(segment);
+        } else {
+            flush(&mut run, &mut out);
+            out.extend_from_slice(segment);
+        }
     }
+    flush(&mut run, &mut out);
 
-    #[test]
-    fn test_buffer_for_each_line_empty() {
-        let buffer = b"";
-        let mut lines = Vec::new();
-        buffer_for_each_line(buffer, |line| {
-            lines.push(line.to_string());
-            false
-        });
-        assert_eq!(lines, Vec::<String>::new());
+    out
+}
+
+fn advise(mmap: &Mmap, advice: libc::c_int) -> io::Result<()> {
+    let ret = unsafe { libc::madvise(mmap.as_ptr() as *mut libc::c_void, mmap.len(), advice) };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
     }
+}
 
-    #[test]
-    fn test_buffer_for_each_line_reverse_empty() {
-        let buffer = b"";
-        let mut lines = Vec::new();
-        buffer_for_each_line_reverse(buffer, |line| {
-            lines.push(line.to_string());
-            false
-        });
-        assert_eq!(lines, Vec::<String>::new());
+/// # Safety
+///
+/// Same as [`mmap_file`].
+pub unsafe fn mmap_file_sequential(filename: &Path) -> io::Result<Mmap> {
+    let (mmap, _) = mmap_file(filename)?;
+    advise(&mmap, libc::MADV_SEQUENTIAL)?;
+    Ok(mmap)
+}
+
+/// # Safety
+///
+/// Same as [`mmap_file`].
+pub unsafe fn mmap_file_random(filename: &Path) -> io::Result<Mmap> {
+    let (mmap, _) = mmap_file(filename)?;
+    advise(&mmap, libc::MADV_RANDOM)?;
+    Ok(mmap)
+}
+
+pub fn line_bounds_at(buf: &[u8], offset: usize) -> Option<(usize, usize)> {
+    if offset >= buf.len() {
+        return None;
     }
 
-    #[test]
-    fn test_file_for_each_line_non_existent() {
-        let result = file_for_each_line("non_existent.txt", |line| {
-            assert!(line.len() > 0);
-            Ok(())
-        });
-        assert!(result.is_err());
+    // A newline byte belongs to the line it terminates
+    let start = buf[..offset].iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+    let end = buf[offset..].iter().position(|&b| b == b'\n').map_or(buf.len(), |i| offset + i);
+
+    Some((start, end))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileToken {
+    len: u64,
+    modified: Option<SystemTime>,
+    dev: u64,
+    ino: u64,
+}
+
+pub fn file_token(path: &Path) -> io::Result<FileToken> {
+    let metadata = std::fs::metadata(path)?;
+    Ok(FileToken {
+        len: metadata.len(),
+        modified: metadata.modified().ok(),
+        dev: metadata.dev(),
+        ino: metadata.ino(),
+    })
+}
+
+pub fn changed_since(path: &Path, token: &FileToken) -> io::Result<bool> {
+    Ok(file_token(path)? != *token)
+}
+
+pub fn buffer_for_each_line_batch<F>(buf: &[u8], batch: usize, mut cb: F)
+where
+    F: FnMut(&[Cow<str>]) -> bool,
+{
+    let batch = batch.max(1);
+    // Lines borrow from buf unless they held invalid UTF-8; the Vec is reused for every batch
+    let mut lines: Vec<Cow<str>> = Vec::with_capacity(batch);
+
+    let mut stopped = false;
+    for_each_line_cow(buf, |line| {
+        lines.push(line);
+        if lines.len() == batch {
+            stopped = cb(&lines);
+            lines.clear();
+        }
+        stopped
+    });
+
+    if !stopped && !lines.is_empty() {
+        cb(&lines);
+    }
+}
+
+pub fn read_file_into(path: &Path, buf: &mut Vec<u8>) -> io::Result<usize> {
+    buf.clear(); // Keeps the capacity for the next read
+    let mut file = File::open(path)?;
+    file.read_to_end(buf)
+}
+
+pub fn position_line<P>(buf: &[u8], pred: P) -> Option<usize>
+where
+    P: Fn(&str) -> bool,
+{
+    let mut index = 0;
+    let mut found = None;
+
+    buffer_for_each_line(buf, |line| {
+        if pred(line) {
+            found = Some(index);
+            return true;
+        }
+        index += 1;
+        false
+    });
+
+    found
+}
+
+const STALE_TEMP_AGE: Duration = Duration::from_secs(60);
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+pub fn cleanup_temp(path: &Path) -> io::Result<()> {
+    let tmp = temp_path(path);
+    let tmp_modified = match std::fs::metadata(&tmp) {
+        Ok(metadata) => metadata.modified()?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    // A recent tmp may still be in the middle of being written; an old one never will be
+    let untouched = tmp_modified.elapsed().is_ok_and(|age| age > STALE_TEMP_AGE);
+    let stale = match std::fs::metadata(path) {
+        Ok(metadata) => untouched || tmp_modified < metadata.modified()?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => untouched,
+        Err(e) => return Err(e),
+    };
+
+    if stale {
+        std::fs::remove_file(&tmp)?;
+    }
+    Ok(())
+}
+
+pub fn read_head_tail(path: &Path, head: usize, tail: usize) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let file = File::open(path)?;
+    let size = file.metadata()?.len();
+
+    let head_len = (head as u64).min(size);
+    // The tail never re-reads bytes already covered by the head
+    let tail_len = (tail as u64).min(size - head_len);
+
+    let mut head_buf = vec![0u8; head_len as usize];
+    file.read_exact_at(&mut head_buf, 0)?;
+    let mut tail_buf = vec![0u8; tail_len as usize];
+    file.read_exact_at(&mut tail_buf, size - tail_len)?;
+
+    Ok((head_buf, tail_buf))
+}
+
+pub fn for_each_section<F>(buf: &[u8], mut cb: F)
+where
+    F: FnMut(&str, &[(String, String)]) -> bool,
+{
+    let mut name = String::new();
+    let mut pairs: Vec<(String, String)> = Vec::new();
+    let mut started = false; // Whether a [section] header has been seen
+    let mut stopped = false;
+
+    buffer_for_each_trimmed_line(buf, |line| {
+        if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+            return false;
+        }
+
+        if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            // The default section is only reported if it has keys
+            if (started || !pairs.is_empty()) && cb(&name, &pairs) {
+                stopped = true;
+                return true;
+            }
+            name = header.trim().to_string();
+            pairs.clear();
+            started = true;
+        } else if let Some((key, value)) = line.split_once('=') {
+            pairs.push((key.trim_end().to_string(), value.trim_start().to_string()));
+        }
+        false
+    });
+
+    if !stopped && (started || !pairs.is_empty()) {
+        cb(&name, &pairs);
+    }
+}
+
+pub fn for_each_window<F>(path: &Path, window: usize, overlap: usize, mut cb: F) -> io::Result<()>
+    where
+        F: FnMut(&[u8]) -> bool,
+{
+    if overlap >= window {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "overlap must be smaller than window"));
     }
-}
\ No newline at end of file
+
+    let mut file = File::open(path)?;
+    let mut buf = vec![0u8; window];
+    let mut carried = 0;
+
+    loop {
+        let (n, eof) = read_some(&mut file, &mut buf[carried..])?;
+        if n == 0 {
+            break; // Nothing new beyond what the previous window already showed
+        }
+        let len = carried + n;
+        if cb(&buf[..len]) || eof {
+            break;
+        }
+
+        // The tail of this window becomes the head of the next one
+        buf.copy_within(len - overlap..len, 0);
+        carried = overlap;
+    }
+
+    Ok(())
+}
+
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = temp_path(path);
+    let mut file = File::create(&tmp)?;
+
+    // The rename would otherwise reset the mode to the umask default
+    match std::fs::metadata(path) {
+        Ok(metadata) => {
+            file.set_permissions(metadata.permissions())?;
+            match std::os::unix::fs::fchown(&file, Some(metadata.uid()), Some(metadata.gid())) {
+                Ok(()) => {},
+                // Giving a file away requires privilege; keep our own ownership then
+                Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => {},
+                Err(e) => return Err(e),
+            }
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+        Err(e) => return Err(e),
+    }
+
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+
+    // rename() replaces the target in one step, so readers see the old or the new file
+    std::fs::rename(&tmp, path).inspect_err(|_| {
+        let _ = std::fs::remove_file(&tmp);
+    })
+}
+
+pub fn buffer_for_each_numbered_line<F>(buf: &[u8], mut cb: F)
+where
+    F: FnMut(usize, &str) -> bool,
+{
+    let mut number = 0;
+    buffer_for_each_line(buf, |line| {
+        number += 1;
+        cb(number, line)
+    });
+}
+
+pub fn file_for_each_line_buffered<F>(path: &Path, capacity: usize, cb: F) -> io::Result<()>
+    where
+        F: FnMut(&str) -> io::Result<()>,
+{
+    if capacity == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "capacity must be greater than zero"));
+    }
+
+    let file = File::open(path)?;
+    reader_for_each_line(io::BufReader::with_capacity(capacity, file), cb)
+}
+
+pub fn utf8_safe_boundary(buf: &[u8]) -> usize {
+    // Walk back over at most three continuation bytes (10xxxxxx) to the last lead byte
+    let mut start = buf.len();
+    while start > 0 && buf.len() - start < 4 {
+        start -= 1;
+        let b = buf[start];
+        if b & 0xc0 != 0x80 {
+            let needed = match b {
+                0x00..=0x7f => 1,
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => return buf.len(), // Not UTF-8 at all; let the decoder deal with it
+            };
+            return if buf.len() - start >= needed { buf.len() } else { start };
+        }
+    }
+    buf.len()
+}
+
+const RETRY_BACKOFF_START: Duration = Duration::from_millis(1);
+const RETRY_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+// EIO is also how a disk reports real damage, so retrying it is left to the caller
+fn is_transient(e: &io::Error, retry_eio: bool) -> bool {
+    e.kind() == io::ErrorKind::WouldBlock || (retry_eio && e.raw_os_error() == Some(libc::EIO))
+}
+
+fn read_retry<R: Read>(reader: &mut R, buf: &mut [u8], max_retries: u32, retry_eio: bool) -> io::Result<usize> {
+    let mut pos = 0;
+    let mut retries = 0;
+    let mut backoff = RETRY_BACKOFF_START;
+
+    while pos < buf.len() {
+        match reader.read(&mut buf[pos..]) {
+            Ok(0) => break, // EOF
+            Ok(n) => {
+                pos += n;
+                retries = 0;
+                backoff = RETRY_BACKOFF_START;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(ref e) if is_transient(e, retry_eio) && retries < max_retries => {
+                retries += 1;
+                std::thread::sleep(backoff);
+                backoff = (backoff * 2).min(RETRY_BACKOFF_MAX);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(pos)
+}
+
+pub fn read_all_retry(fd: RawFd, buf: &mut [u8], max_retries: u32, retry_eio: bool) -> io::Result<usize> {
+    // The caller keeps ownership of `fd`, so never close it here.
+    let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+    // Pipes and sockets can't seek; they are read from wherever they are
+    match file.seek(SeekFrom::Start(0)) {
+        Ok(_) => {},
+        Err(ref e) if e.raw_os_error() == Some(libc::ESPIPE) => {},
+        Err(e) => return Err(e),
+    }
+    read_retry(&mut *file, buf, max_retries, retry_eio)
+}
+
+pub fn for_each_command<F>(buf: &[u8], mut cb: F)
+where
+    F: FnMut(&str) -> bool,
+{
+    buffer_for_each_trimmed_line(buf, |line| {
+        if line.is_empty() || line.starts_with('#') {
+            return false;
+        }
+        // "\#" at the start is a literal hash, not a comment
+        match line.strip_prefix('\\') {
+            Some(rest) if rest.starts_with('#') => cb(rest),
+            _ => cb(line),
+        }
+    });
+}
+
+pub fn read_exact_all(fd: RawFd, buf: &mut [u8]) -> io::Result<()> {
+    // The caller keeps ownership of `fd`, so never close it here.
+    let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+    file.seek(SeekFrom::Start(0))?;
+
+    // With no retries this is exactly read_all's loop: only EINTR is retried
+    let n = read_retry(&mut *file, buf, 0, false)?;
+    if n < buf.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("expected {} bytes, file has {}", buf.len(), n),
+        ));
+    }
+    Ok(())
+}
+
+pub fn nth_newline_offset(buf: &[u8], n: usize) -> Option<usize> {
+    if n == 0 {
+        return Some(0); // The first line starts at the beginning
+    }
+    buf.iter()
+        .enumerate()
+        .filter(|&(_, &b)| b == b'\n')
+        .nth(n - 1)
+        .map(|(i, _)| i + 1)
+}
+
+pub fn open_fifo_read(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+
+    if !std::fs::metadata(path)?.file_type().is_fifo() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a FIFO"));
+    }
+    // Without O_NONBLOCK, open() would block until a writer shows up
+    File::options().read(true).custom_flags(libc::O_NONBLOCK).open(path)
+}
+
+// Waits for data or a hangup; returns false if `timeout` passes first
+fn wait_readable(file: &File, timeout: Option<Duration>) -> io::Result<bool> {
+    use std::os::fd::AsRawFd;
+
+    let mut pfd = libc::pollfd { fd: file.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+    // Round up, so a timeout under a millisecond doesn't turn into a busy loop
+    let timeout_ms = timeout.map_or(-1, |t| t.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32);
+    loop {
+        let ret = unsafe { libc::poll(&mut pfd, 1, timeout_ms) };
+        if ret >= 0 {
+            return Ok(ret > 0);
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+pub fn read_fifo(file: &mut File, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+    let deadline = Instant::now() + timeout;
+    let mut pos = 0;
+
+    while pos < buf.len() {
+        match file.read(&mut buf[pos..]) {
+            Ok(0) => break, // No writer has the FIFO open
+            Ok(n) => pos += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                // A writer that stays connected but silent mustn't block forever
+                if !wait_readable(file, Some(remaining))? {
+                    if pos == 0 {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "read deadline passed"));
+                    }
+                    break;
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(pos)
+}
+
+const REVERSE_CHUNK_SIZE: usize = 8 * 1024;
+
+pub fn file_for_each_line_reverse<F>(path: &Path, cb: F) -> io::Result<()>
+    where
+        F: FnMut(&str) -> io::Result<()>,
+{
+    file_for_each_line_reverse_chunked(path, REVERSE_CHUNK_SIZE, cb)
+}
+
+pub fn file_for_each_line_reverse_chunked<F>(path: &Path, chunk: usize, mut cb: F) -> io::Result<()>
+    where
+        F: FnMut(&str) -> io::Result<()>,
+{
+    if chunk == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "chunk must be greater than zero"));
+    }
+
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut pos = len;
+    // The start of a line whose beginning lies in an earlier chunk
+    let mut tail: Vec<u8> = Vec::new();
+
+    while pos > 0 {
+        let size = (chunk as u64).min(pos) as usize;
+        pos -= size as u64;
+        let mut data = vec![0u8; size];
+        file.read_exact_at(&mut data, pos)?;
+        data.extend_from_slice(&tail);
+
+        if pos + size as u64 == len && data.last() == Some(&b'\n') {
+            data.pop(); // The final newline ends the last line rather than starting an empty one
+        }
+
+        let mut end = data.len();
+        while let Some(i) = data[..end].iter().rposition(|&b| b == b'\n') {
+            cb(&bytes_to_str_lossy(strip_cr(&data[i + 1..end])))?;
+            end = i;
+        }
+        data.truncate(end);
+        tail = data;
+    }
+
+    if len > 0 {
+        cb(&bytes_to_str_lossy(strip_cr(&tail)))?;
+    }
+
+    Ok(())
+}
+
+fn sum_sizes(paths: &[PathBuf], strict: bool) -> io::Result<u64> {
+    let mut total = 0;
+    for path in paths {
+        match std::fs::metadata(path) {
+            Ok(metadata) => total += metadata.len(),
+            Err(e) if strict => return Err(e),
+            Err(_) => {} // Removed or unreadable since it was listed
+        }
+    }
+    Ok(total)
+}
+
+pub fn total_size(paths: &[PathBuf]) -> io::Result<u64> {
+    sum_sizes(paths, false)
+}
+
+pub fn total_size_strict(paths: &[PathBuf]) -> io::Result<u64> {
+    sum_sizes(paths, true)
+}
+
+pub fn buffer_for_each_line_utf16<F>(buf: &[u8], little_endian: bool, mut cb: F)
+where
+    F: FnMut(&str) -> bool,
+{
+    let unit = |pair: &[u8]| {
+        if little_endian {
+            u16::from_le_bytes([pair[0], pair[1]])
+        } else {
+            u16::from_be_bytes([pair[0], pair[1]])
+        }
+    };
+    // A trailing odd byte isn't a whole code unit and is dropped
+    let units: &[u8] = &buf[..buf.len() & !1];
+    let mut pos = if units.len() >= 2 && unit(&units[..2]) == 0xfeff { 2 } else { 0 };
+    let mut line = String::new();
+
+    while pos < units.len() {
+        let mut end = pos;
+        while end < units.len() && unit(&units[end..end + 2]) != 0x000a {
+            end += 2;
+        }
+        let mut line_end = end;
+        if line_end > pos && unit(&units[line_end - 2..line_end]) == 0x000d {
+            line_end -= 2;
+        }
+
+        line.clear();
+        line.extend(char::decode_utf16(units[pos..line_end].chunks_exact(2).map(unit))
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)));
+        pos = end + 2;
+
+        if cb(&line) {
+            break;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+}
+
+pub fn detect_encoding(buf: &[u8]) -> Encoding {
+    if buf.starts_with(b"\xff\xfe") {
+        return Encoding::Utf16Le;
+    }
+    if buf.starts_with(b"\xfe\xff") {
+        return Encoding::Utf16Be;
+    }
+
+    // Without a BOM, mostly-ASCII UTF-16 shows up as a zero in every other byte
+    let sample = &buf[..buf.len().min(512) & !1];
+    let pairs = sample.len() / 2;
+    let zeros_odd = sample.chunks_exact(2).filter(|pair| pair[1] == 0).count();
+    let zeros_even = sample.chunks_exact(2).filter(|pair| pair[0] == 0).count();
+    if pairs > 0 && zeros_odd * 2 > pairs {
+        Encoding::Utf16Le
+    } else if pairs > 0 && zeros_even * 2 > pairs {
+        Encoding::Utf16Be
+    } else {
+        Encoding::Utf8
+    }
+}
+
+pub fn for_each_line_auto<F>(buf: &[u8], cb: F) -> Encoding
+where
+    F: FnMut(&str) -> bool,
+{
+    let encoding = detect_encoding(buf);
+    match encoding {
+        Encoding::Utf8 => buffer_for_each_line(buf.strip_prefix(b"\xef\xbb\xbf").unwrap_or(buf), cb),
+        Encoding::Utf16Le => buffer_for_each_line_utf16(buf, true, cb),
+        Encoding::Utf16Be => buffer_for_each_line_utf16(buf, false, cb),
+    }
+    encoding
+}
+
+pub fn write_all_periodic_sync(file: &File, buf: &[u8], sync_every: usize) -> io::Result<usize> {
+    if sync_every == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "sync_every must be greater than zero"));
+    }
+
+    let mut writer = file;
+    let mut written = 0;
+    for chunk in buf.chunks(sync_every) {
+        writer.write_all(chunk)?;
+        // Everything before this chunk is already on disk if we crash here
+        file.sync_data()?;
+        written += chunk.len();
+    }
+
+    Ok(written)
+}
+
+pub fn split_header_body(buf: &[u8]) -> (&[u8], &[u8]) {
+    let mut pos = 0;
+
+    while let Some(len) = buf[pos..].iter().position(|&b| b == b'\n') {
+        let end = pos + len;
+        if strip_cr(&buf[pos..end]).is_empty() {
+            // The header doesn't include the newline of its last line
+            let header = buf[..pos].strip_suffix(b"\n").unwrap_or(&buf[..pos]);
+            return (strip_cr(header), &buf[end + 1..]);
+        }
+        pos = end + 1;
+    }
+
+    (buf, &[])
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn rename_exchange(a: &Path, b: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let to_c = |path: &Path| CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte"));
+    let (a, b) = (to_c(a)?, to_c(b)?);
+    let ret = unsafe {
+        libc::renameat2(libc::AT_FDCWD, a.as_ptr(), libc::AT_FDCWD, b.as_ptr(), libc::RENAME_EXCHANGE)
+    };
+    if ret == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+fn rename_exchange(_a: &Path, _b: &Path) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+// Three renames: not atomic, and a failure part way leaves one file under a temporary name
+pub fn swap_files_nonatomic(a: &Path, b: &Path) -> io::Result<()> {
+    let mut name = a.as_os_str().to_os_string();
+    name.push(".swap");
+    let tmp = PathBuf::from(name);
+
+    // Claim the temporary name first, so an unrelated file already called that isn't replaced
+    std::fs::OpenOptions::new().write(true).create_new(true).open(&tmp)?;
+    if let Err(e) = std::fs::rename(a, &tmp) {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(e);
+    }
+    if let Err(e) = std::fs::rename(b, a) {
+        let _ = std::fs::rename(&tmp, a);
+        return Err(e);
+    }
+    std::fs::rename(&tmp, b).map_err(|e| io::Error::new(e.kind(), format!(
+        "swap left incomplete: {} is now at {} ({})", a.display(), tmp.display(), e
+    )))
+}
+
+pub fn swap_files(a: &Path, b: &Path) -> io::Result<()> {
+    match rename_exchange(a, b) {
+        Ok(()) => Ok(()),
+        // Kernels before 3.15 or filesystems without RENAME_EXCHANGE support
+        Err(ref e) if e.kind() == io::ErrorKind::Unsupported
+            || e.raw_os_error() == Some(libc::EINVAL)
+            || e.raw_os_error() == Some(libc::ENOSYS) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "atomic swap isn't supported here; swap_files_nonatomic swaps through a temporary name",
+            )),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn for_each_record_block<F, G>(buf: &[u8], is_start: G, mut cb: F)
+where
+    G: Fn(&str) -> bool,
+    F: FnMut(&[&str]) -> bool,
+{
+    let mut lines: Vec<Cow<str>> = Vec::new();
+    let mut starts = Vec::new();
+
+    for_each_line_cow(buf, |line| {
+        // A new record closes the previous one, or the preamble if there was one
+        if is_start(&line) && !lines.is_empty() {
+            starts.push(lines.len());
+        }
+        lines.push(line);
+        false
+    });
+
+    let refs: Vec<&str> = lines.iter().map(|line| line.as_ref()).collect();
+    let mut begin = 0;
+    for end in starts.into_iter().chain(Some(refs.len())) {
+        if begin < end && cb(&refs[begin..end]) {
+            return;
+        }
+        begin = end;
+    }
+}
+
+pub fn read_u32_le(buf: &[u8], offset: usize) -> Option<u32> {
+    let bytes = buf.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+pub fn read_u64_le(buf: &[u8], offset: usize) -> Option<u64> {
+    let bytes = buf.get(offset..offset.checked_add(8)?)?;
+    Some(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+pub fn read_i32_le(buf: &[u8], offset: usize) -> Option<i32> {
+    read_u32_le(buf, offset).map(|value| value as i32)
+}
+
+fn read_to_end_deadline<R: Read>(reader: &mut R, deadline: Instant) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut chunk = [0u8; 8192];
+
+    loop {
+        if Instant::now() >= deadline {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "read deadline passed"));
+        }
+        match reader.read(&mut chunk) {
+            Ok(0) => return Ok(data),
+            Ok(n) => data.extend_from_slice(&chunk[..n]),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Polls before each read, so a pipe or socket that stalls can't block past the deadline
+struct DeadlineReader<'a> {
+    file: &'a File,
+    deadline: Instant,
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if !wait_readable(self.file, Some(remaining))? {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "read deadline passed"));
+        }
+        let mut file = self.file;
+        file.read(buf)
+    }
+}
+
+pub fn read_to_vec_deadline(fd: RawFd, deadline: Instant) -> io::Result<Vec<u8>> {
+    // The caller keeps ownership of `fd`, so never close it here.
+    let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+    read_to_end_deadline(&mut DeadlineReader { file: &file, deadline }, deadline)
+}
+
+pub fn buffer_for_each_line_windowed<F>(buf: &[u8], mut cb: F)
+where
+    F: FnMut(Option<&str>, &str) -> bool,
+{
+    let mut prev: Option<String> = None;
+
+    buffer_for_each_line(buf, |line| {
+        let stop = cb(prev.as_deref(), line);
+        // Reuse the allocation for the next line's copy
+        let prev = prev.get_or_insert_with(String::new);
+        prev.clear();
+        prev.push_str(line);
+        stop
+    });
+}
+
+pub fn estimate_line_count(path: &Path, sample_bytes: usize) -> io::Result<usize> {
+    if sample_bytes == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "sample_bytes must be greater than zero"));
+    }
+
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut sample = Vec::with_capacity(sample_bytes.min(len as usize));
+    file.take(sample_bytes as u64).read_to_end(&mut sample)?;
+
+    let newlines = sample.iter().filter(|&&b| b == b'\n').count();
+    if sample.len() as u64 >= len {
+        // The sample is the whole file, so the count is exact
+        let unterminated = sample.last().is_some_and(|&b| b != b'\n');
+        return Ok(newlines + unterminated as usize);
+    }
+
+    // Assume the rest of the file has the same average line length as the sample
+    let estimate = newlines as f64 * len as f64 / sample.len() as f64;
+    Ok((estimate.round() as usize).max(1))
+}
+
+pub fn read_file_with_line_count(path: &Path) -> io::Result<(Vec<u8>, usize)> {
+    let mut file = File::open(path)?;
+    let mut data = Vec::with_capacity(file.metadata()?.len() as usize);
+    let mut chunk = [0u8; 64 * 1024];
+    let mut newlines = 0;
+
+    loop {
+        let n = match file.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        // Count while the chunk is still in cache
+        newlines += chunk[..n].iter().filter(|&&b| b == b'\n').count();
+        data.extend_from_slice(&chunk[..n]);
+    }
+
+    // Same count as buffer_for_each_line: a last line without a newline still counts
+    let unterminated = data.last().is_some_and(|&b| b != b'\n');
+    Ok((data, newlines + unterminated as usize))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bom {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+}
+
+impl Bom {
+    pub fn as_bytes(self) -> &'static [u8] {
+        match self {
+            Bom::Utf8 => b"\xef\xbb\xbf",
+            Bom::Utf16Le => b"\xff\xfe",
+            Bom::Utf16Be => b"\xfe\xff",
+        }
+    }
+}
+
+pub fn strip_bom_tracked(buf: &[u8]) -> (&[u8], Option<Bom>) {
+    for bom in [Bom::Utf8, Bom::Utf16Le, Bom::Utf16Be] {
+        if let Some(rest) = buf.strip_prefix(bom.as_bytes()) {
+            return (rest, Some(bom));
+        }
+    }
+    (buf, None)
+}
+
+pub fn sniff_magic(path: &Path, magics: &[(&[u8], &'static str)]) -> io::Result<Option<&'static str>> {
+    let file = File::open(path)?;
+    let longest = magics.iter().map(|(magic, _)| magic.len()).max().unwrap_or(0);
+    // A file shorter than the longest magic can still match a shorter one
+    let len = (longest as u64).min(file.metadata()?.len()) as usize;
+    let mut header = vec![0u8; len];
+    file.read_exact_at(&mut header, 0)?;
+
+    Ok(magics.iter().find(|(magic, _)| header.starts_with(magic)).map(|(_, label)| *label))
+}
+
+pub fn read_since(path: &Path, last_offset: u64) -> io::Result<(Vec<String>, u64)> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    // An offset past the end means the log was truncated or rotated
+    let start = if last_offset > len { 0 } else { last_offset };
+
+    file.seek(SeekFrom::Start(start))?;
+    let mut data = Vec::with_capacity((len - start) as usize);
+    file.read_to_end(&mut data)?;
+
+    // A line without its newline may still be being written, so leave it for next time
+    let complete = data.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+    let mut lines = Vec::new();
+    buffer_for_each_line(&data[..complete], |line| {
+        lines.push(line.to_string());
+        false
+    });
+
+    Ok((lines, start + complete as u64))
+}
+
+pub fn contents_equal(path: &Path, other: &[u8]) -> io::Result<bool> {
+    let len = std::fs::metadata(path)?.len();
+    if len != other.len() as u64 {
+        return Ok(false);
+    }
+    if len == 0 {
+        return Ok(true); // Empty files can't be mapped
+    }
+
+    let (data, _) = unsafe { mmap_file(path)? };
+    Ok(data[..] == *other)
+}
+
+pub fn transform_file<F>(src: &Path, dst: &Path, mut transform: F) -> io::Result<()>
+where
+    F: FnMut(&[u8], &mut Vec<u8>),
+{
+    let mut file = File::open(src)?;
+    let mut chunk = vec![0u8; 64 * 1024];
+    let mut out = Vec::with_capacity(file.metadata()?.len() as usize);
+
+    loop {
+        let n = match file.read(&mut chunk) {
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        // The final empty chunk lets a stateful transform flush what it held back
+        transform(&chunk[..n], &mut out);
+        if n == 0 {
+            break;
+        }
+    }
+
+    write_atomic(dst, &out)
+}
+
+pub fn read_lines(path: &Path) -> io::Result<Vec<String>> {
+    let data = std::fs::read(path)?;
+    let mut lines = Vec::new();
+    buffer_for_each_line(&data, |line| {
+        lines.push(line.to_string());
+        false
+    });
+    Ok(lines)
+}
+
+pub struct MmapSlice {
+    map: Option<Mmap>, // None for an empty range, which can't be mapped
+}
+
+impl Deref for MmapSlice {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.map.as_deref().unwrap_or(&[])
+    }
+}
+
+pub fn mmap_slice(path: &Path, range: Range<u64>) -> io::Result<MmapSlice> {
+    let file = File::open(path)?;
+    let size = file.metadata()?.len();
+    if range.start > range.end || range.end > size {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "range is outside the file"));
+    }
+    let len = usize::try_from(range.end - range.start)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "range is too large to map"))?;
+    if len == 0 {
+        return Ok(MmapSlice { map: None });
+    }
+
+    // memmap does the page alignment; same caveat as mmap_file: the file must not shrink while mapped
+    let map = unsafe { MmapOptions::new().offset(range.start).len(len).map(&file)? };
+    Ok(MmapSlice { map: Some(map) })
+}
+
+fn file_mtime(path: &Path) -> io::Result<SystemTime> {
+    std::fs::metadata(path)?.modified()
+}
+
+pub fn scan_dir_by_mtime(root: &Path, extensions: &[&str], newest_first: bool) -> io::Result<Vec<(PathBuf, Option<SystemTime>)>> {
+    Ok(sort_by_mtime(scan_dir(root, extensions)?, newest_first, file_mtime))
+}
+
+// scan_dir_by_mtime with the mtime lookup passed in, so tests can make it fail
+fn sort_by_mtime<M>(paths: Vec<PathBuf>, newest_first: bool, mtime: M) -> Vec<(PathBuf, Option<SystemTime>)>
+where
+    M: Fn(&Path) -> io::Result<SystemTime>,
+{
+    let mut files: Vec<(PathBuf, Option<SystemTime>)> = paths
+        .into_iter()
+        .map(|path| {
+            let mtime = mtime(&path).ok();
+            (path, mtime)
+        })
+        .collect();
+
+    // Unreadable mtimes sort last either way; the stable sort keeps scan order among ties
+    files.sort_by(|(_, a), (_, b)| match (a, b) {
+        (Some(a), Some(b)) if newest_first => b.cmp(a),
+        (Some(a), Some(b)) => a.cmp(b),
+        (a, b) => b.is_some().cmp(&a.is_some()),
+    });
+    files
+}
+
+pub fn write_lines(path: &Path, lines: &[&str], newline: Newline) -> io::Result<()> {
+    let mut data = Vec::with_capacity(lines.iter().map(|line| line.len() + 2).sum());
+    for line in lines {
+        // A line that already carries its terminator mustn't end up with two
+        let line = line.strip_suffix('\n').map_or(*line, |line| line.strip_suffix('\r').unwrap_or(line));
+        data.extend_from_slice(line.as_bytes());
+        data.extend_from_slice(newline.as_bytes());
+    }
+    write_atomic(path, &data)
+}
+
+pub fn is_valid_utf8(path: &Path) -> io::Result<bool> {
+    if std::fs::metadata(path)?.len() == 0 {
+        return Ok(true); // Empty files can't be mapped
+    }
+
+    let (data, _) = unsafe { mmap_file(path)? };
+    Ok(std::str::from_utf8(&data).is_ok())
+}
+
+// Lets a caller parse a large input a few lines at a time, e.g. once per frame
+pub struct IncrementalLineReader<R: BufRead> {
+    reader: R,
+    line: String,
+}
+
+impl<R: BufRead> IncrementalLineReader<R> {
+    pub fn new(reader: R) -> Self {
+        IncrementalLineReader { reader, line: String::new() }
+    }
+
+    pub fn next_n(&mut self, n: usize, mut cb: impl FnMut(&str)) -> io::Result<usize> {
+        let mut count = 0;
+        while count < n && read_line_stripped(&mut self.reader, &mut self.line)? {
+            cb(&self.line);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+pub fn scan_counts(root: &Path, extensions: &[&str]) -> io::Result<HashMap<PathBuf, usize>> {
+    let mut counts = HashMap::new();
+    scan_dir_progress(root, extensions, |path| {
+        // Files are always found below root, so they always have a parent
+        if let Some(dir) = path.parent() {
+            *counts.entry(dir.to_path_buf()).or_insert(0) += 1;
+        }
+    })?;
+    Ok(counts)
+}
+
+pub fn for_each_reverse_chunk<F>(path: &Path, chunk: usize, mut cb: F) -> io::Result<()>
+    where
+        F: FnMut(u64, &[u8]) -> bool,
+{
+    if chunk == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "chunk must be greater than zero"));
+    }
+
+    let file = File::open(path)?;
+    let mut pos = file.metadata()?.len();
+    let mut data = vec![0u8; chunk];
+
+    // Chunks are counted back from the end, so only the one at the start of the file can be short
+    while pos > 0 {
+        let size = (chunk as u64).min(pos) as usize;
+        pos -= size as u64;
+        file.read_exact_at(&mut data[..size], pos)?;
+        if cb(pos, &data[..size]) {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{OpenOptions, remove_file};
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn test_read_all() {
+        let mut file = OpenOptions::new().write(true).read(true).create(true).truncate(true).open("testfile_read_all.txt").unwrap();
+        file.write_all(b"Hello, world!").unwrap();
+        file.sync_all().unwrap(); // Ensure all writes are flushed to the file
+        let fd = file.as_raw_fd();
+        let mut buffer = [0u8; 13];
+        let result = read_all(fd, &mut buffer);
+        let bytes_read = result.unwrap();
+        println!("Read {} bytes", bytes_read ); // Print the number of bytes read
+        println!("Buffer contents: {:?}", &buffer); // Print the contents of the buffer
+        assert_eq!(bytes_read, 13);
+        assert_eq!(&buffer, b"Hello, world!");
+
+        remove_file("testfile_read_all.txt").unwrap();
+    }
+
+    #[test]
+    fn test_write_all() {
+        let file = OpenOptions::new().write(true).read(true).create(true).truncate(true).open("testfile_write_all.txt").unwrap();
+        let result = write_all(&file, b"Hello, world!");
+        assert_eq!(result.unwrap(), 13);
+        remove_file("testfile_write_all.txt").unwrap();
+    }
+
+    #[test]
+    fn test_mmap_file() {
+        std::fs::write("testfile_mmap_file.txt", b"Hello, world!").unwrap();
+        let result = unsafe { mmap_file(Path::new("testfile_mmap_file.txt")) };
+        assert!(result.is_ok());
+        remove_file("testfile_mmap_file.txt").unwrap();
+    }
+
+    #[test]
+    fn test_buffer_for_each_line() {
+        let buffer = b"Hello\nworld\n";
+        let mut lines = Vec::new();
+        buffer_for_each_line(buffer, |line| {
+            lines.push(line.to_string());
+            false
+        });
+        assert_eq!(lines, vec!["Hello", "world"]);
+    }
+
+    #[test]
+    fn test_buffer_for_each_line_reverse() {
+        let buffer = b"Hello\nworld\n";
+        let mut lines = Vec::new();
+        buffer_for_each_line_reverse(buffer, |line| {
+            lines.push(line.to_string());
+            false
+        });
+        assert_eq!(lines, vec!["world", "Hello"]);
+    }
+
+    #[test]
+    fn test_file_for_each_line() {
+        std::fs::write("testfile_file_for_each_line.txt", b"Hello, world!").unwrap();
+        let result = file_for_each_line("testfile_file_for_each_line.txt", |line| {
+            assert!(!line.is_empty());
+            Ok(())
+        });
+        assert!(result.is_ok());
+        remove_file("testfile_file_for_each_line.txt").unwrap();
+    }
+
+
+    #[test]
+    fn test_read_all_empty_file() {
+        let path = Path::new("empty.txt");
+        if !path.exists() {
+            File::create(path).expect("Failed to create file 'empty.txt'");
+        }
+        let file = File::open(path).expect("Failed to open file 'empty.txt'");
+        let fd = file.as_raw_fd();
+        let mut buffer = [0u8; 10];
+        let result = read_all(fd, &mut buffer);
+        assert_eq!(result.unwrap(), 0);
+        remove_file(path).unwrap()
+    }
+
+    #[test]
+    fn test_write_all_empty_buffer() {
+        let file = OpenOptions::new().write(true).read(true).create(true).truncate(true).open("testfile_write_all_empty.txt").unwrap();
+        let result = write_all(&file, b"");
+        assert_eq!(result.unwrap(), 0);
+        remove_file("testfile_write_all_empty.txt").unwrap();
+    }
+
+    #[test]
+    fn test_mmap_file_non_existent() {
+        let result = unsafe { mmap_file(Path::new("non_existent.txt")) };
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_buffer_for_each_line_empty() {
+        let buffer = b"";
+        let mut lines = Vec::new();
+        buffer_for_each_line(buffer, |line| {
+            lines.push(line.to_string());
+            false
+        });
+        assert_eq!(lines, Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_buffer_for_each_line_reverse_empty() {
+        let buffer = b"";
+        let mut lines = Vec::new();
+        buffer_for_each_line_reverse(buffer, |line| {
+            lines.push(line.to_string());
+            false
+        });
+        assert_eq!(lines, Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_file_for_each_line_non_existent() {
+        let result = file_for_each_line("non_existent.txt", |line| {
+            assert!(!line.is_empty());
+            Ok(())
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_for_each_logical_line() {
+        let buffer = b"set a=1 \\\n  b=2\nnext\n";
+        let mut lines = Vec::new();
+        for_each_logical_line(buffer, |line| {
+            lines.push(line.to_string());
+            false
+        });
+        assert_eq!(lines, vec!["set a=1   b=2", "next"]);
+    }
+
+    #[test]
+    fn test_for_each_logical_line_escaped_backslash() {
+        let buffer = b"path=C:\\\\\nnext\n";
+        let mut lines = Vec::new();
+        for_each_logical_line(buffer, |line| {
+            lines.push(line.to_string());
+            false
+        });
+        assert_eq!(lines, vec!["path=C:\\\\", "next"]);
+    }
+
+    #[test]
+    fn test_read_file_smart() {
+        let path = Path::new("testfile_read_file_smart.txt");
+        std::fs::write(path, b"Hello, world!").unwrap();
+
+        let small = read_file_smart(path, 13).unwrap();
+        assert!(matches!(small, FileData::Owned(_)));
+        assert_eq!(&*small, b"Hello, world!");
+
+        let large = read_file_smart(path, 12).unwrap();
+        assert!(matches!(large, FileData::Mapped(_)));
+        assert_eq!(&*large, b"Hello, world!");
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_first_line() {
+        let path = Path::new("testfile_read_first_line.txt");
+        std::fs::write(path, b"#EXTM3U\r\n#EXTINF:1,Title\r\ntrack.mp3\r\n").unwrap();
+        assert_eq!(read_first_line(path).unwrap(), Some("#EXTM3U".to_string()));
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_first_line_empty_file() {
+        let path = Path::new("testfile_read_first_line_empty.txt");
+        File::create(path).unwrap();
+        assert_eq!(read_first_line(path).unwrap(), None);
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_first_line_no_newline() {
+        let path = Path::new("testfile_read_first_line_no_newline.txt");
+        std::fs::write(path, b"[playlist]").unwrap();
+        assert_eq!(read_first_line(path).unwrap(), Some("[playlist]".to_string()));
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_buffer_for_each_trimmed_line() {
+        let buffer = b"  set a=1\n\tset b=2  \r\n\n";
+        let mut lines = Vec::new();
+        buffer_for_each_trimmed_line(buffer, |line| {
+            lines.push(line.to_string());
+            false
+        });
+        assert_eq!(lines, vec!["set a=1", "set b=2", ""]);
+    }
+
+    #[test]
+    fn test_for_each_line_with_pos() {
+        let path = "testfile_for_each_line_with_pos.txt";
+        std::fs::write(path, b"first\r\nsecond\n").unwrap();
+        let mut seen = Vec::new();
+        let result = for_each_line_with_pos(path, |line, pos| {
+            seen.push((line.to_string(), pos));
+            Ok(())
+        });
+        assert!(result.is_ok());
+        assert_eq!(seen, vec![("first".to_string(), 7), ("second".to_string(), 14)]);
+        assert_eq!(seen.last().unwrap().1, std::fs::metadata(path).unwrap().len());
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_some() {
+        let path = Path::new("testfile_read_some.txt");
+        std::fs::write(path, b"Hello, world!").unwrap();
+        let mut file = File::open(path).unwrap();
+        let mut buffer = [0u8; 64];
+        let (bytes_read, eof) = read_some(&mut file, &mut buffer).unwrap();
+        assert_eq!(bytes_read, 13);
+        assert!(eof);
+        assert_eq!(&buffer[..bytes_read], b"Hello, world!");
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_some_full_buffer() {
+        let path = Path::new("testfile_read_some_full.txt");
+        std::fs::write(path, b"Hello, world!").unwrap();
+        let mut file = File::open(path).unwrap();
+        let mut buffer = [0u8; 5];
+        assert_eq!(read_some(&mut file, &mut buffer).unwrap(), (5, false));
+        assert_eq!(&buffer, b"Hello");
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_buffer_for_each_line_checked() {
+        let buffer = b"valid\n\xff\xfe\r\nstill valid\n";
+        let mut lines = Vec::new();
+        buffer_for_each_line_checked(buffer, |line| {
+            lines.push(line.map(|s| s.to_string()).map_err(|b| b.to_vec()));
+            false
+        });
+        assert_eq!(lines, vec![
+            Ok("valid".to_string()),
+            Err(vec![0xff, 0xfe]),
+            Ok("still valid".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_scan_dir_progress() {
+        let root = Path::new("testdir_scan_dir_progress");
+        std::fs::create_dir_all(root.join("album")).unwrap();
+        std::fs::write(root.join("album/01.mp3"), b"").unwrap();
+        std::fs::write(root.join("album/02.FLAC"), b"").unwrap();
+        std::fs::write(root.join("album/cover.jpg"), b"").unwrap();
+        std::fs::write(root.join("single.ogg"), b"").unwrap();
+
+        let mut streamed = Vec::new();
+        scan_dir_progress(root, &["mp3", "flac", "ogg"], |path| streamed.push(path.to_path_buf())).unwrap();
+        assert_eq!(streamed, vec![
+            root.join("album/01.mp3"),
+            root.join("album/02.FLAC"),
+            root.join("single.ogg"),
+        ]);
+        assert_eq!(streamed, scan_dir(root, &["mp3", "flac", "ogg"]).unwrap());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_collapse_blank_lines() {
+        let buffer = b"one\r\n\n\n\ntwo\n\nthree\n  \n\t\n";
+        let result = collapse_blank_lines(buffer, Newline::Lf);
+        assert_eq!(result, b"one\r\n\ntwo\n\nthree\n\n");
+    }
+
+    #[test]
+    fn test_mmap_file_sequential() {
+        let path = Path::new("testfile_mmap_file_sequential.txt");
+        std::fs::write(path, b"Hello, world!").unwrap();
+
+        let mmap = unsafe { mmap_file_sequential(path) }.unwrap();
+        assert_eq!(&mmap[..], b"Hello, world!");
+        assert!(advise(&mmap, libc::MADV_SEQUENTIAL).is_ok());
+
+        let mmap = unsafe { mmap_file_random(path) }.unwrap();
+        assert_eq!(&mmap[..], b"Hello, world!");
+        assert!(advise(&mmap, libc::MADV_RANDOM).is_ok());
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_line_bounds_at() {
+        let buffer = b"first\nsecond\nthird";
+        assert_eq!(line_bounds_at(buffer, 6), Some((6, 12))); // Line start
+        assert_eq!(line_bounds_at(buffer, 9), Some((6, 12))); // Middle
+        assert_eq!(line_bounds_at(buffer, 12), Some((6, 12))); // On the newline
+        assert_eq!(line_bounds_at(buffer, 0), Some((0, 5)));
+        assert_eq!(line_bounds_at(buffer, 15), Some((13, 18)));
+    }
+
+    #[test]
+    fn test_line_bounds_at_out_of_range() {
+        assert_eq!(line_bounds_at(b"first\n", 6), None);
+        assert_eq!(line_bounds_at(b"", 0), None);
+    }
+
+    #[test]
+    fn test_changed_since() {
+        let path = Path::new("testfile_changed_since.txt");
+        let replacement = Path::new("testfile_changed_since.new");
+        std::fs::write(path, b"Hello, world!").unwrap();
+
+        let token = file_token(path).unwrap();
+        assert!(!changed_since(path, &token).unwrap());
+
+        // Same size and possibly the same mtime, but a different file
+        std::fs::write(replacement, b"Hello, there!").unwrap();
+        std::fs::rename(replacement, path).unwrap();
+        assert!(changed_since(path, &token).unwrap());
+
+        let token = file_token(path).unwrap();
+        std::fs::write(path, b"Hello").unwrap();
+        assert!(changed_since(path, &token).unwrap());
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_buffer_for_each_line_batch() {
+        let buffer = b"1\n2\n3\n4\n5\n6\n7\n";
+        let mut batches = Vec::new();
+        buffer_for_each_line_batch(buffer, 3, |lines| {
+            batches.push(lines.iter().map(|line| line.to_string()).collect::<Vec<_>>());
+            false
+        });
+        assert_eq!(batches, vec![vec!["1", "2", "3"], vec!["4", "5", "6"], vec!["7"]]);
+
+        // Returning true stops before the final partial batch; lines split like buffer_for_each_line
+        let mut batches = Vec::new();
+        buffer_for_each_line_batch(b"a\r\nb\xff\nc", 2, |lines| {
+            // Valid lines are borrowed from the buffer; only the replacement is owned
+            assert!(matches!(lines[0], Cow::Borrowed("a")));
+            assert!(matches!(lines[1], Cow::Owned(_)));
+            batches.push(lines.iter().map(|line| line.to_string()).collect::<Vec<_>>());
+            true
+        });
+        assert_eq!(batches, vec![vec!["a", "b\u{fffd}"]]);
+    }
+
+    #[test]
+    fn test_scan_dir_permission_denied() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = Path::new("testdir_scan_dir_permission_denied");
+        let locked = root.join("locked");
+        std::fs::create_dir_all(&locked).unwrap();
+        std::fs::create_dir_all(root.join("open")).unwrap();
+        std::fs::write(locked.join("hidden.mp3"), b"").unwrap();
+        std::fs::write(root.join("open/track.mp3"), b"").unwrap();
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+
+        let mut found = Vec::new();
+        let mut skipped = Vec::new();
+        let result = scan_dir_report(root, &["mp3"], |path| found.push(path.to_path_buf()), Some(&mut skipped));
+        let scanned = scan_dir(root, &["mp3"]);
+
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        assert!(result.is_ok());
+        assert!(found.contains(&root.join("open/track.mp3")));
+        assert_eq!(scanned.unwrap(), found);
+
+        // Root ignores directory permissions, so fail the listing directly to cover the report
+        let mut found = Vec::new();
+        let mut skipped = Vec::new();
+        scan_dir_with(root, &["mp3"], |path| found.push(path.to_path_buf()), Some(&mut skipped), |dir| {
+            if dir == locked {
+                return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+            }
+            std::fs::read_dir(dir)
+        }).unwrap();
+        assert_eq!(found, vec![root.join("open/track.mp3")]);
+        assert_eq!(skipped, vec![locked.clone()]);
+
+        // A subdirectory deleted mid-scan is passed over without being reported as skipped
+        let mut found = Vec::new();
+        let mut skipped = Vec::new();
+        scan_dir_with(root, &["mp3"], |path| found.push(path.to_path_buf()), Some(&mut skipped), |dir| {
+            if dir == locked {
+                return Err(io::Error::from(io::ErrorKind::NotFound));
+            }
+            std::fs::read_dir(dir)
+        }).unwrap();
+        assert_eq!(found, vec![root.join("open/track.mp3")]);
+        assert!(skipped.is_empty());
+
+        // Any other error still stops the scan
+        let result = scan_dir_with(root, &["mp3"], |_| {}, None, |dir| {
+            if dir.ends_with("open") {
+                return Err(io::Error::from(io::ErrorKind::InvalidData));
+            }
+            std::fs::read_dir(dir)
+        });
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_read_file_into() {
+        let large = Path::new("testfile_read_file_into_large.txt");
+        let small = Path::new("testfile_read_file_into_small.txt");
+        std::fs::write(large, vec![b'x'; 4096]).unwrap();
+        std::fs::write(small, b"Hello, world!").unwrap();
+
+        let mut buffer = Vec::new();
+        assert_eq!(read_file_into(large, &mut buffer).unwrap(), 4096);
+        assert_eq!(buffer.len(), 4096);
+        let capacity = buffer.capacity();
+
+        assert_eq!(read_file_into(small, &mut buffer).unwrap(), 13);
+        assert_eq!(buffer, b"Hello, world!");
+        assert_eq!(buffer.capacity(), capacity);
+
+        remove_file(large).unwrap();
+        remove_file(small).unwrap();
+    }
+
+    #[test]
+    fn test_position_line() {
+        let buffer = b"a=1\n[playlist]\nFile1=x.mp3\n[other]\n";
+        assert_eq!(position_line(buffer, |line| line.starts_with('[')), Some(1));
+    }
+
+    #[test]
+    fn test_position_line_absent() {
+        let buffer = b"a=1\nb=2\n";
+        assert_eq!(position_line(buffer, |line| line.starts_with('[')), None);
+    }
+
+    #[test]
+    fn test_cleanup_temp() {
+        let path = Path::new("testfile_cleanup_temp.txt");
+        let tmp = Path::new("testfile_cleanup_temp.txt.tmp");
+        std::fs::write(tmp, b"half written").unwrap();
+        std::fs::write(path, b"complete").unwrap();
+        let old = SystemTime::now() - Duration::from_secs(3600);
+        File::options().write(true).open(tmp).unwrap().set_modified(old).unwrap();
+
+        cleanup_temp(path).unwrap();
+        assert!(!tmp.exists());
+        assert!(path.exists());
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_cleanup_temp_in_progress() {
+        let path = Path::new("testfile_cleanup_temp_in_progress.txt");
+        let tmp = Path::new("testfile_cleanup_temp_in_progress.txt.tmp");
+        std::fs::write(path, b"complete").unwrap();
+        let old = SystemTime::now() - Duration::from_secs(3600);
+        File::options().write(true).open(path).unwrap().set_modified(old).unwrap();
+        std::fs::write(tmp, b"being written").unwrap();
+
+        cleanup_temp(path).unwrap();
+        assert!(tmp.exists());
+
+        // Left over from a crash: newer than the target, but untouched for an hour
+        File::options().write(true).open(path).unwrap().set_modified(old - Duration::from_secs(60)).unwrap();
+        File::options().write(true).open(tmp).unwrap().set_modified(old).unwrap();
+        cleanup_temp(path).unwrap();
+        assert!(!tmp.exists());
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_buffer_for_each_field() {
+        let buffer = b"one.mp3\0two words.mp3\0\0three\r.mp3\0";
+        let mut fields = Vec::new();
+        buffer_for_each_field(buffer, 0, |field| {
+            fields.push(field.to_vec());
+            false
+        });
+        assert_eq!(fields, vec![
+            b"one.mp3".to_vec(),
+            b"two words.mp3".to_vec(),
+            b"".to_vec(),
+            b"three\r.mp3".to_vec(),
+        ]);
+    }
+
+    #[test]
+    fn test_read_head_tail() {
+        let path = Path::new("testfile_read_head_tail.txt");
+        std::fs::write(path, b"0123456789").unwrap();
+
+        let (head, tail) = read_head_tail(path, 3, 2).unwrap();
+        assert_eq!(head, b"012");
+        assert_eq!(tail, b"89");
+
+        // Overlapping regions: the tail only returns what the head didn't
+        let (head, tail) = read_head_tail(path, 6, 6).unwrap();
+        assert_eq!(head, b"012345");
+        assert_eq!(tail, b"6789");
+
+        let (head, tail) = read_head_tail(path, 128, 128).unwrap();
+        assert_eq!(head, b"0123456789");
+        assert!(tail.is_empty());
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_for_each_section() {
+        let buffer = b"version=2\n\n[playlist]\nFile1 = a.mp3\n; comment\nTitle1=A\n[extra]\nkey=value\n";
+        let mut sections = Vec::new();
+        for_each_section(buffer, |name, pairs| {
+            sections.push((name.to_string(), pairs.to_vec()));
+            false
+        });
+
+        let pair = |k: &str, v: &str| (k.to_string(), v.to_string());
+        assert_eq!(sections, vec![
+            ("".to_string(), vec![pair("version", "2")]),
+            ("playlist".to_string(), vec![pair("File1", "a.mp3"), pair("Title1", "A")]),
+            ("extra".to_string(), vec![pair("key", "value")]),
+        ]);
+    }
+
+    #[test]
+    fn test_line_callbacks_random_bytes() {
+        // xorshift, so the test is deterministic without a rand dependency
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for _ in 0..500 {
+            let len = (next() % 64) as usize;
+            let buffer: Vec<u8> = (0..len).map(|_| match next() % 4 {
+                0 => b'\n',
+                1 => b'\r',
+                2 => b'\\',
+                _ => next() as u8,
+            }).collect();
+
+            buffer_for_each_line(&buffer, |_| false);
+            buffer_for_each_line_reverse(&buffer, |_| false);
+            buffer_for_each_line_batch(&buffer, 3, |_| false);
+            buffer_for_each_trimmed_line(&buffer, |_| false);
+            for_each_logical_line(&buffer, |_| false);
+        }
+    }
+
+    #[test]
+    fn test_buffer_for_each_line_invalid_utf8() {
+        let buffer = b"ok\n\xffbad\n";
+        let mut lines = Vec::new();
+        buffer_for_each_line(buffer, |line| {
+            lines.push(line.to_string());
+            false
+        });
+        assert_eq!(lines, vec!["ok", "\u{fffd}bad"]);
+    }
+
+    #[test]
+    fn test_for_each_window() {
+        let path = Path::new("testfile_for_each_window.txt");
+        let mut data = vec![b'.'; 40];
+        data[14..20].copy_from_slice(b"NEEDLE"); // Straddles the first 16-byte window
+        std::fs::write(path, &data).unwrap();
+
+        let mut windows = Vec::new();
+        let mut found = false;
+        for_each_window(path, 16, 5, |chunk| {
+            windows.push(chunk.len());
+            found = chunk.windows(6).any(|w| w == b"NEEDLE");
+            found
+        }).unwrap();
+        assert!(found);
+        assert_eq!(windows, vec![16, 16]);
+
+        let mut windows = Vec::new();
+        for_each_window(path, 16, 5, |chunk| {
+            windows.push(chunk.len());
+            false
+        }).unwrap();
+        assert_eq!(windows, vec![16, 16, 16, 7]); // 11 new bytes per window after the first
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_for_each_window_invalid_overlap() {
+        let result = for_each_window(Path::new("non_existent.txt"), 8, 8, |_| false);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let path = Path::new("testfile_write_atomic.txt");
+        std::fs::write(path, b"old contents").unwrap();
+
+        write_atomic(path, b"new").unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"new");
+        assert!(!Path::new("testfile_write_atomic.txt.tmp").exists());
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_write_atomic_preserves_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = Path::new("testfile_write_atomic_preserves_mode.txt");
+        std::fs::write(path, b"old contents").unwrap();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o640)).unwrap();
+
+        write_atomic(path, b"new").unwrap();
+        let metadata = std::fs::metadata(path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o640);
+        assert_eq!(std::fs::read(path).unwrap(), b"new");
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_buffer_for_each_numbered_line() {
+        let buffer = b"first\r\nsecond\n\nfourth";
+        let mut lines = Vec::new();
+        buffer_for_each_numbered_line(buffer, |number, line| {
+            lines.push((number, line.to_string()));
+            false
+        });
+        assert_eq!(lines[0], (1, "first".to_string()));
+        assert_eq!(lines[3], (4, "fourth".to_string()));
+        assert_eq!(lines.len(), 4);
+    }
+
+    #[test]
+    fn test_file_for_each_line_buffered() {
+        let path = "testfile_file_for_each_line_buffered.txt";
+        let contents: String = (0..20_000).map(|i| format!("/music/track{:05}.mp3\n", i)).collect();
+        std::fs::write(path, &contents).unwrap();
+
+        let mut buffered = Vec::new();
+        file_for_each_line_buffered(Path::new(path), 1 << 20, |line| {
+            buffered.push(line.to_string());
+            Ok(())
+        }).unwrap();
+        let mut plain = Vec::new();
+        file_for_each_line(path, |line| {
+            plain.push(line.to_string());
+            Ok(())
+        }).unwrap();
+
+        assert_eq!(buffered.len(), 20_000);
+        assert_eq!(buffered, plain);
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_file_for_each_line_buffered_zero_capacity() {
+        let result = file_for_each_line_buffered(Path::new("non_existent.txt"), 0, |_| Ok(()));
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_utf8_safe_boundary() {
+        let text = "caf\u{e9} \u{20ac}".as_bytes(); // "é" is 2 bytes, "€" is 3
+        assert_eq!(utf8_safe_boundary(text), text.len());
+        assert_eq!(utf8_safe_boundary(&text[..text.len() - 1]), text.len() - 3);
+        assert_eq!(utf8_safe_boundary(&text[..text.len() - 2]), text.len() - 3);
+        assert_eq!(utf8_safe_boundary(&text[..4]), 3); // Cut inside "é"
+        assert_eq!(utf8_safe_boundary(b"ascii"), 5);
+        assert_eq!(utf8_safe_boundary(b""), 0);
+    }
+
+    #[test]
+    fn test_utf8_safe_boundary_partial_only() {
+        assert_eq!(utf8_safe_boundary(&[0xe2, 0x82]), 0);
+        assert_eq!(utf8_safe_boundary(&[0xf0, 0x9f, 0x8e]), 0);
+    }
+
+    struct FlakyReader<'a> {
+        failures: u32,
+        errno: i32,
+        data: &'a [u8],
+    }
+
+    impl Read for FlakyReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(io::Error::from_raw_os_error(self.errno));
+            }
+            let n = self.data.len().min(buf.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_read_all_retry() {
+        let mut reader = FlakyReader { failures: 2, errno: libc::EAGAIN, data: b"Hello, world!" };
+        let mut buffer = [0u8; 13];
+        assert_eq!(read_retry(&mut reader, &mut buffer, 3, false).unwrap(), 13);
+        assert_eq!(&buffer, b"Hello, world!");
+
+        let mut reader = FlakyReader { failures: 2, errno: libc::EAGAIN, data: b"Hello, world!" };
+        let result = read_retry(&mut reader, &mut buffer, 1, false);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+        // EIO is only retried when asked for
+        let mut reader = FlakyReader { failures: 1, errno: libc::EIO, data: b"Hello, world!" };
+        let result = read_retry(&mut reader, &mut buffer, 3, false);
+        assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EIO));
+        let mut reader = FlakyReader { failures: 2, errno: libc::EIO, data: b"Hello, world!" };
+        assert_eq!(read_retry(&mut reader, &mut buffer, 3, true).unwrap(), 13);
+
+        let path = Path::new("testfile_read_all_retry.txt");
+        std::fs::write(path, b"Hello, world!").unwrap();
+        let file = File::open(path).unwrap();
+        let mut buffer = [0u8; 32];
+        assert_eq!(read_all_retry(file.as_raw_fd(), &mut buffer, 3, false).unwrap(), 13);
+        remove_file(path).unwrap();
+
+        // A pipe can't seek, so it is read from its current position
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (reader, mut writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        writer.write_all(b"from a pipe").unwrap();
+        drop(writer);
+        assert_eq!(read_all_retry(reader.as_raw_fd(), &mut buffer, 3, false).unwrap(), 11);
+        assert_eq!(&buffer[..11], b"from a pipe");
+    }
+
+    #[test]
+    fn test_for_each_command() {
+        let buffer = b"# cmus rc\n  set softvol=true  \n\n\t# indented comment\n\\#not-a-comment\nbind common q quit\n";
+        let mut commands = Vec::new();
+        for_each_command(buffer, |command| {
+            commands.push(command.to_string());
+            false
+        });
+        assert_eq!(commands, vec!["set softvol=true", "#not-a-comment", "bind common q quit"]);
+    }
+
+    #[test]
+    fn test_read_exact_all_short_file() {
+        let path = Path::new("testfile_read_exact_all_short.txt");
+        std::fs::write(path, b"Hello").unwrap();
+        let file = File::open(path).unwrap();
+
+        let mut buffer = [0u8; 10];
+        let result = read_exact_all(file.as_raw_fd(), &mut buffer);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        // The fd is still open and usable afterwards
+        let mut buffer = [0u8; 5];
+        read_exact_all(file.as_raw_fd(), &mut buffer).unwrap();
+        assert_eq!(&buffer, b"Hello");
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_nth_newline_offset() {
+        let buffer = b"one\ntwo\r\nthree\n";
+        assert_eq!(nth_newline_offset(buffer, 0), Some(0));
+        assert_eq!(nth_newline_offset(buffer, 2), Some(9));
+        assert_eq!(&buffer[9..], b"three\n");
+        assert_eq!(nth_newline_offset(buffer, 3), Some(15));
+    }
+
+    #[test]
+    fn test_nth_newline_offset_beyond_end() {
+        assert_eq!(nth_newline_offset(b"one\ntwo", 2), None);
+        assert_eq!(nth_newline_offset(b"", 1), None);
+    }
+
+    #[test]
+    fn test_open_fifo_read() {
+        let path = Path::new("testfile_open_fifo_read.fifo");
+        let c_path = std::ffi::CString::new("testfile_open_fifo_read.fifo").unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+
+        let mut reader = open_fifo_read(path).unwrap();
+        // A reader exists now, so opening the write end doesn't block
+        let mut writer = File::options().write(true).open(path).unwrap();
+        let handle = std::thread::spawn(move || {
+            writer.write_all(b"hello").unwrap();
+            std::thread::sleep(Duration::from_millis(20));
+            writer.write_all(b" fifo").unwrap();
+        });
+
+        let mut buffer = [0u8; 64];
+        let n = read_fifo(&mut reader, &mut buffer, Duration::from_secs(10)).unwrap();
+        handle.join().unwrap();
+        assert_eq!(&buffer[..n], b"hello fifo");
+
+        // A writer that never writes or closes runs into the timeout instead of hanging the read
+        let mut writer = File::options().write(true).open(path).unwrap();
+        let result = read_fifo(&mut reader, &mut buffer, Duration::from_millis(50));
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        writer.write_all(b"partial").unwrap();
+        let n = read_fifo(&mut reader, &mut buffer, Duration::from_millis(50)).unwrap();
+        assert_eq!(&buffer[..n], b"partial");
+        drop(writer);
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_open_fifo_read_not_fifo() {
+        let path = Path::new("testfile_open_fifo_read_not_fifo.txt");
+        std::fs::write(path, b"").unwrap();
+        assert_eq!(open_fifo_read(path).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_file_for_each_line_reverse_chunked() {
+        let path = Path::new("testfile_file_for_each_line_reverse_chunked.txt");
+        std::fs::write(path, "first\r\nsecond\n\na much longer third line\nlast\n").unwrap();
+
+        let mut lines = Vec::new();
+        file_for_each_line_reverse_chunked(path, 4, |line| {
+            lines.push(line.to_string());
+            Ok(())
+        }).unwrap();
+        assert_eq!(lines, vec!["last", "a much longer third line", "", "second", "first"]);
+
+        let mut default_lines = Vec::new();
+        file_for_each_line_reverse(path, |line| {
+            default_lines.push(line.to_string());
+            Ok(())
+        }).unwrap();
+        assert_eq!(default_lines, lines);
+
+        let result = file_for_each_line_reverse_chunked(path, 0, |_| Ok(()));
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_total_size() {
+        let paths: Vec<PathBuf> = (0..3)
+            .map(|i| PathBuf::from(format!("testfile_total_size_{}.txt", i)))
+            .collect();
+        for (i, path) in paths.iter().enumerate() {
+            std::fs::write(path, vec![b'x'; 100 * (i + 1)]).unwrap();
+        }
+        assert_eq!(total_size(&paths).unwrap(), 600);
+
+        let mut with_missing = paths.clone();
+        with_missing.push(PathBuf::from("non_existent_total_size.txt"));
+        assert_eq!(total_size(&with_missing).unwrap(), 600);
+        assert_eq!(total_size_strict(&with_missing).unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        for path in &paths {
+            remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_buffer_for_each_line_utf16_le() {
+        let buf: Vec<u8> = "\u{feff}Début\r\nSecond ♪\r\n".encode_utf16().flat_map(|u| u.to_le_bytes()).collect();
+        let mut lines = Vec::new();
+        buffer_for_each_line_utf16(&buf, true, |line| {
+            lines.push(line.to_string());
+            false
+        });
+        assert_eq!(lines, vec!["Début", "Second ♪"]);
+    }
+
+    #[test]
+    fn test_buffer_for_each_line_utf16_be() {
+        let buf: Vec<u8> = "first\n𝄞 second".encode_utf16().flat_map(|u| u.to_be_bytes()).collect();
+        let mut lines = Vec::new();
+        buffer_for_each_line_utf16(&buf, false, |line| {
+            lines.push(line.to_string());
+            false
+        });
+        assert_eq!(lines, vec!["first", "𝄞 second"]);
+    }
+
+    #[test]
+    fn test_for_each_line_auto_utf8() {
+        let mut lines = Vec::new();
+        let encoding = for_each_line_auto("\u{feff}#EXTM3U\r\nCafé.mp3\n".as_bytes(), |line| {
+            lines.push(line.to_string());
+            false
+        });
+        assert_eq!(encoding, Encoding::Utf8);
+        assert_eq!(lines, vec!["#EXTM3U", "Café.mp3"]);
+    }
+
+    #[test]
+    fn test_for_each_line_auto_utf16le() {
+        for text in ["\u{feff}#EXTM3U\r\nCafé.mp3\r\n", "#EXTM3U\r\nCafé.mp3\r\n"] {
+            let buf: Vec<u8> = text.encode_utf16().flat_map(|u| u.to_le_bytes()).collect();
+            let mut lines = Vec::new();
+            let encoding = for_each_line_auto(&buf, |line| {
+                lines.push(line.to_string());
+                false
+            });
+            assert_eq!(encoding, Encoding::Utf16Le);
+            assert_eq!(lines, vec!["#EXTM3U", "Café.mp3"]);
+        }
+    }
+
+    #[test]
+    fn test_write_all_periodic_sync() {
+        let path = Path::new("testfile_write_all_periodic_sync.bin");
+        let data: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+
+        let file = File::create(path).unwrap();
+        assert_eq!(write_all_periodic_sync(&file, &data, 64 * 1024).unwrap(), data.len());
+        assert_eq!(std::fs::read(path).unwrap(), data);
+
+        let result = write_all_periodic_sync(&file, &data, 0);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        remove_file(path).unwrap();
+    }
+
+    struct InterruptingReader<'a> {
+        inner: io::Cursor<&'a [u8]>,
+        interrupt_at_line: usize,
+        lines_read: usize,
+    }
+
+    impl Read for InterruptingReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl BufRead for InterruptingReader<'_> {
+        fn fill_buf(&mut self) -> io::Result<&[u8]> {
+            self.inner.fill_buf()
+        }
+
+        fn consume(&mut self, amt: usize) {
+            self.inner.consume(amt)
+        }
+
+        fn read_line(&mut self, buf: &mut String) -> io::Result<usize> {
+            if self.lines_read == self.interrupt_at_line {
+                self.interrupt_at_line = usize::MAX;
+                return Err(io::Error::from(io::ErrorKind::Interrupted));
+            }
+            self.lines_read += 1;
+            self.inner.read_line(buf)
+        }
+    }
+
+    #[test]
+    fn test_file_for_each_line_interrupted() {
+        let reader = InterruptingReader {
+            inner: io::Cursor::new(b"first\r\nsecond\nthird"),
+            interrupt_at_line: 1,
+            lines_read: 0,
+        };
+        let mut lines = Vec::new();
+        reader_for_each_line(reader, |line| {
+            lines.push(line.to_string());
+            Ok(())
+        }).unwrap();
+        assert_eq!(lines, vec!["first", "second", "third"]);
+    }
+
+    #[test]
+    fn test_split_header_body() {
+        assert_eq!(split_header_body(b"a\nb\n\nc\nd"), (&b"a\nb"[..], &b"c\nd"[..]));
+        assert_eq!(split_header_body(b"a\r\nb\r\n\r\nc\r\n"), (&b"a\r\nb"[..], &b"c\r\n"[..]));
+        assert_eq!(split_header_body(b"\nbody"), (&b""[..], &b"body"[..]));
+        assert_eq!(split_header_body(b"no\nblank line"), (&b"no\nblank line"[..], &b""[..]));
+    }
+
+    #[test]
+    fn test_swap_files() {
+        let a = Path::new("testfile_swap_files_a.m3u");
+        let b = Path::new("testfile_swap_files_b.m3u");
+        std::fs::write(a, b"old playlist").unwrap();
+        std::fs::write(b, b"new playlist").unwrap();
+
+        swap_files(a, b).unwrap();
+        assert_eq!(std::fs::read(a).unwrap(), b"new playlist");
+        assert_eq!(std::fs::read(b).unwrap(), b"old playlist");
+
+        swap_files_nonatomic(a, b).unwrap();
+        assert_eq!(std::fs::read(a).unwrap(), b"old playlist");
+        assert_eq!(std::fs::read(b).unwrap(), b"new playlist");
+
+        // An existing file under the temporary name is left alone
+        let tmp = Path::new("testfile_swap_files_a.m3u.swap");
+        std::fs::write(tmp, b"someone else's file").unwrap();
+        assert_eq!(swap_files_nonatomic(a, b).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read(tmp).unwrap(), b"someone else's file");
+        assert_eq!(std::fs::read(a).unwrap(), b"old playlist");
+        remove_file(tmp).unwrap();
+
+        remove_file(a).unwrap();
+        let result = swap_files(a, b);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::NotFound);
+        remove_file(b).unwrap();
+    }
+
+    #[test]
+    fn test_for_each_record_block() {
+        let buffer = b"PERFORMER \"Miles Davis\"\nFILE \"kind.flac\" WAVE\n  TRACK 01 AUDIO\n    TITLE \"So What\"\n    INDEX 01 00:00:00\n  TRACK 02 AUDIO\r\n    TITLE \"Freddie Freeloader\"\r\n    INDEX 01 09:22:00\r\n";
+        let mut blocks = Vec::new();
+        for_each_record_block(buffer, |line| line.trim_start().starts_with("TRACK "), |block| {
+            blocks.push(block.iter().map(|line| line.trim().to_string()).collect::<Vec<_>>());
+            false
+        });
+        assert_eq!(blocks, vec![
+            vec!["PERFORMER \"Miles Davis\"", "FILE \"kind.flac\" WAVE"],
+            vec!["TRACK 01 AUDIO", "TITLE \"So What\"", "INDEX 01 00:00:00"],
+            vec!["TRACK 02 AUDIO", "TITLE \"Freddie Freeloader\"", "INDEX 01 09:22:00"],
+        ]);
+
+        let mut count = 0;
+        for_each_record_block(buffer, |line| line.trim_start().starts_with("TRACK "), |_| {
+            count += 1;
+            true
+        });
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_read_u32_le() {
+        let buffer = [0x78, 0x56, 0x34, 0x12, 0xff, 0xff, 0xff, 0xff, 0x01];
+        assert_eq!(read_u32_le(&buffer, 0), Some(0x12345678));
+        assert_eq!(read_i32_le(&buffer, 4), Some(-1));
+        assert_eq!(read_u64_le(&buffer, 0), Some(0xffffffff_12345678));
+        assert_eq!(read_u64_le(&buffer, 1), Some(0x01ffffff_ff123456));
+    }
+
+    #[test]
+    fn test_read_u32_le_past_end() {
+        let buffer = [0u8; 6];
+        assert_eq!(read_u32_le(&buffer, 3), None);
+        assert_eq!(read_i32_le(&buffer, 6), None);
+        assert_eq!(read_u64_le(&buffer, 0), None);
+        assert_eq!(read_u32_le(&buffer, usize::MAX), None);
+    }
+
+    struct SlowReader {
+        delay: Duration,
+        chunks: usize,
+    }
+
+    impl Read for SlowReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.chunks == 0 {
+                return Ok(0);
+            }
+            std::thread::sleep(self.delay);
+            self.chunks -= 1;
+            buf[..4].copy_from_slice(b"data");
+            Ok(4)
+        }
+    }
+
+    #[test]
+    fn test_read_to_vec_deadline() {
+        // Every read makes progress, but the whole transfer takes too long
+        let mut reader = SlowReader { delay: Duration::from_millis(20), chunks: 50 };
+        let result = read_to_end_deadline(&mut reader, Instant::now() + Duration::from_millis(100));
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+
+        let mut reader = SlowReader { delay: Duration::from_millis(1), chunks: 3 };
+        let data = read_to_end_deadline(&mut reader, Instant::now() + Duration::from_secs(5)).unwrap();
+        assert_eq!(data, b"datadatadata");
+
+        let path = Path::new("testfile_read_to_vec_deadline.txt");
+        std::fs::write(path, b"Hello, world!").unwrap();
+        let file = File::open(path).unwrap();
+        let data = read_to_vec_deadline(file.as_raw_fd(), Instant::now() + Duration::from_secs(5)).unwrap();
+        assert_eq!(data, b"Hello, world!");
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_to_vec_deadline_stalled_pipe() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (reader, writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        (&writer).write_all(b"partial").unwrap();
+
+        // The writer stays open without sending more, so a plain read would block forever
+        let result = read_to_vec_deadline(reader.as_raw_fd(), Instant::now() + Duration::from_millis(50));
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        drop(writer);
+    }
+
+    #[test]
+    fn test_buffer_for_each_line_windowed() {
+        let buffer = b"#EXTINF:562,Miles Davis - So What\r\nso_what.flac\nfreddie.flac\n";
+        let mut pairs = Vec::new();
+        buffer_for_each_line_windowed(buffer, |prev, line| {
+            pairs.push((prev.map(str::to_string), line.to_string()));
+            false
+        });
+        assert_eq!(pairs, vec![
+            (None, "#EXTINF:562,Miles Davis - So What".to_string()),
+            (Some("#EXTINF:562,Miles Davis - So What".to_string()), "so_what.flac".to_string()),
+            (Some("so_what.flac".to_string()), "freddie.flac".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_estimate_line_count() {
+        let path = Path::new("testfile_estimate_line_count.txt");
+        let contents: String = (0..10_000).map(|i| format!("/music/artist/album/track {:05}.flac\n", i)).collect();
+        std::fs::write(path, &contents).unwrap();
+
+        let estimate = estimate_line_count(path, 4096).unwrap();
+        assert!((9_900..=10_100).contains(&estimate), "estimate was {}", estimate);
+        assert_eq!(estimate_line_count(path, contents.len()).unwrap(), 10_000);
+
+        std::fs::write(path, b"a\nb\nc").unwrap();
+        assert_eq!(estimate_line_count(path, 1024).unwrap(), 3);
+        assert_eq!(estimate_line_count(path, 0).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_file_with_line_count() {
+        let path = Path::new("testfile_read_file_with_line_count.txt");
+        for contents in [&b"one\ntwo\r\n\nfour"[..], b"one\ntwo\r\n\nfour\n", b""] {
+            std::fs::write(path, contents).unwrap();
+            let mut expected = 0;
+            buffer_for_each_line(contents, |_| {
+                expected += 1;
+                false
+            });
+
+            let (data, count) = read_file_with_line_count(path).unwrap();
+            assert_eq!(data, contents);
+            assert_eq!(count, expected);
+        }
+        assert_eq!(read_file_with_line_count(path).unwrap().1, 0);
+
+        std::fs::write(path, b"one\ntwo\r\n\nfour").unwrap();
+        assert_eq!(read_file_with_line_count(path).unwrap().1, 4);
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_strip_bom_tracked() {
+        assert_eq!(strip_bom_tracked(b"\xef\xbb\xbf#EXTM3U"), (&b"#EXTM3U"[..], Some(Bom::Utf8)));
+        assert_eq!(strip_bom_tracked(b"\xff\xfe#\0"), (&b"#\0"[..], Some(Bom::Utf16Le)));
+        assert_eq!(strip_bom_tracked(b"\xfe\xff\0#"), (&b"\0#"[..], Some(Bom::Utf16Be)));
+    }
+
+    #[test]
+    fn test_strip_bom_tracked_no_bom() {
+        assert_eq!(strip_bom_tracked(b"#EXTM3U"), (&b"#EXTM3U"[..], None));
+        assert_eq!(strip_bom_tracked(b"\xef\xbb"), (&b"\xef\xbb"[..], None));
+
+        // Stripping and re-adding the BOM gives back the original bytes
+        let original = b"\xef\xbb\xbfCaf\xc3\xa9.mp3\n";
+        let (content, bom) = strip_bom_tracked(original);
+        assert_eq!([bom.unwrap().as_bytes(), content].concat(), original);
+    }
+
+    const AUDIO_MAGICS: &[(&[u8], &str)] = &[
+        (b"fLaC", "flac"),
+        (b"ID3", "mp3"),
+        (b"OggS", "ogg"),
+        (b"RIFF", "wav"),
+    ];
+
+    #[test]
+    fn test_sniff_magic() {
+        let flac = Path::new("testfile_sniff_magic.flac");
+        let ogg = Path::new("testfile_sniff_magic.ogg");
+        std::fs::write(flac, b"fLaC\0\0\0\x22").unwrap();
+        std::fs::write(ogg, b"OggS\0\x02").unwrap();
+
+        assert_eq!(sniff_magic(flac, AUDIO_MAGICS).unwrap(), Some("flac"));
+        assert_eq!(sniff_magic(ogg, AUDIO_MAGICS).unwrap(), Some("ogg"));
+
+        remove_file(flac).unwrap();
+        remove_file(ogg).unwrap();
+    }
+
+    #[test]
+    fn test_sniff_magic_no_match() {
+        let path = Path::new("testfile_sniff_magic_no_match.txt");
+        std::fs::write(path, b"ID").unwrap();
+        assert_eq!(sniff_magic(path, AUDIO_MAGICS).unwrap(), None);
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_since() {
+        let path = Path::new("testfile_read_since.log");
+        std::fs::write(path, b"played So What\nplayed Blue in Green\n").unwrap();
+        let (lines, offset) = read_since(path, 0).unwrap();
+        assert_eq!(lines, vec!["played So What", "played Blue in Green"]);
+        assert_eq!(offset, 36);
+
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(b"played All Blues\nplayed Flamenco").unwrap();
+        let (lines, offset) = read_since(path, offset).unwrap();
+        assert_eq!(lines, vec!["played All Blues"]);
+
+        file.write_all(b" Sketches\n").unwrap();
+        let (lines, offset) = read_since(path, offset).unwrap();
+        assert_eq!(lines, vec!["played Flamenco Sketches"]);
+        assert_eq!(read_since(path, offset).unwrap(), (vec![], offset));
+
+        std::fs::write(path, b"played Freddie Freeloader\n").unwrap();
+        let (lines, _) = read_since(path, offset).unwrap();
+        assert_eq!(lines, vec!["played Freddie Freeloader"]);
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_contents_equal() {
+        let path = Path::new("testfile_contents_equal.bin");
+        std::fs::write(path, b"cached library").unwrap();
+        assert!(contents_equal(path, b"cached library").unwrap());
+        assert!(!contents_equal(path, b"cached library, longer").unwrap());
+        assert!(!contents_equal(path, b"cached lIbrary").unwrap());
+
+        std::fs::write(path, b"").unwrap();
+        assert!(contents_equal(path, b"").unwrap());
+        assert!(!contents_equal(path, b"x").unwrap());
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_transform_file() {
+        let src = Path::new("testfile_transform_file_src.txt");
+        let dst = Path::new("testfile_transform_file_dst.txt");
+        let contents: String = (0..20_000).map(|i| format!("track {}\n", i)).collect();
+        std::fs::write(src, &contents).unwrap();
+
+        transform_file(src, dst, |chunk, out| out.extend(chunk.iter().map(u8::to_ascii_uppercase))).unwrap();
+        assert_eq!(std::fs::read_to_string(dst).unwrap(), contents.to_uppercase());
+
+        // Numbering lines carries state across chunks, whose boundaries fall mid-line
+        let mut number = 0;
+        let mut at_line_start = true;
+        transform_file(src, dst, |chunk, out| {
+            for &b in chunk {
+                if at_line_start {
+                    number += 1;
+                    out.extend_from_slice(format!("{}: ", number).as_bytes());
+                    at_line_start = false;
+                }
+                out.push(b);
+                at_line_start = b == b'\n';
+            }
+        }).unwrap();
+        let numbered: String = (0..20_000).map(|i| format!("{}: track {}\n", i + 1, i)).collect();
+        assert_eq!(std::fs::read_to_string(dst).unwrap(), numbered);
+
+        remove_file(src).unwrap();
+        remove_file(dst).unwrap();
+    }
+
+    #[test]
+    fn test_read_lines() {
+        let path = Path::new("testfile_read_lines.txt");
+        std::fs::write(path, b"one\r\ntwo\n\nthree\n").unwrap();
+        assert_eq!(read_lines(path).unwrap(), vec!["one", "two", "", "three"]);
+
+        // The last line counts even without its newline
+        std::fs::write(path, b"one\ntwo").unwrap();
+        assert_eq!(read_lines(path).unwrap(), vec!["one", "two"]);
+
+        std::fs::write(path, b"").unwrap();
+        assert_eq!(read_lines(path).unwrap(), Vec::<String>::new());
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_mmap_slice() {
+        let path = Path::new("testfile_mmap_slice.bin");
+        let data: Vec<u8> = (0..20000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(path, &data).unwrap();
+
+        assert_eq!(&mmap_slice(path, 100..200).unwrap()[..], &data[100..200]);
+        // A range starting past the first page
+        assert_eq!(&mmap_slice(path, 5000..20000).unwrap()[..], &data[5000..]);
+        assert!(mmap_slice(path, 300..300).unwrap().is_empty());
+
+        assert_eq!(mmap_slice(path, 100..20001).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+        #[allow(clippy::reversed_empty_ranges)]
+        let reversed = 200..100;
+        assert_eq!(mmap_slice(path, reversed).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_scan_dir_by_mtime() {
+        let dir = Path::new("testdir_scan_dir_by_mtime");
+        std::fs::create_dir_all(dir.join("album")).unwrap();
+        let base = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        for (name, age) in [("album/b.flac", 30), ("a.mp3", 10), ("album/c.flac", 20)] {
+            let file = File::create(dir.join(name)).unwrap();
+            file.set_modified(base - Duration::from_secs(age)).unwrap();
+        }
+        std::fs::write(dir.join("notes.txt"), b"not audio").unwrap();
+
+        let names = |files: Vec<(PathBuf, Option<SystemTime>)>| -> Vec<String> {
+            files.iter().map(|(path, _)| path.file_name().unwrap().to_string_lossy().into_owned()).collect()
+        };
+        let newest = scan_dir_by_mtime(dir, &["flac", "mp3"], true).unwrap();
+        assert_eq!(newest[0].1, Some(base - Duration::from_secs(10)));
+        assert_eq!(names(newest), vec!["a.mp3", "c.flac", "b.flac"]);
+        assert_eq!(names(scan_dir_by_mtime(dir, &["flac", "mp3"], false).unwrap()), vec!["b.flac", "c.flac", "a.mp3"]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_scan_dir_by_mtime_unreadable() {
+        let base = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let paths = vec![PathBuf::from("gone.flac"), PathBuf::from("old.flac"), PathBuf::from("new.flac")];
+        let mtime = |path: &Path| match path.to_str().unwrap() {
+            "old.flac" => Ok(base),
+            "new.flac" => Ok(base + Duration::from_secs(60)),
+            _ => Err(io::Error::from(io::ErrorKind::NotFound)),
+        };
+
+        // A file whose time can't be read has no time, not the epoch, and sorts last both ways
+        let newest = sort_by_mtime(paths.clone(), true, mtime);
+        assert_eq!(newest, vec![
+            (PathBuf::from("new.flac"), Some(base + Duration::from_secs(60))),
+            (PathBuf::from("old.flac"), Some(base)),
+            (PathBuf::from("gone.flac"), None),
+        ]);
+        let oldest = sort_by_mtime(paths, false, mtime);
+        assert_eq!(oldest.last(), Some(&(PathBuf::from("gone.flac"), None)));
+        assert_eq!(oldest[0].0, PathBuf::from("old.flac"));
+    }
+
+    #[test]
+    fn test_write_lines() {
+        let path = Path::new("testfile_write_lines.txt");
+        write_lines(path, &["set softvol=true", "", "bind common q quit"], Newline::Lf).unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"set softvol=true\n\nbind common q quit\n");
+
+        write_lines(path, &["one", "two\r\n"], Newline::CrLf).unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"one\r\ntwo\r\n");
+        write_lines(path, &["one", "two\n"], Newline::Lf).unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"one\ntwo\n");
+
+        write_lines(path, &[], Newline::Lf).unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"");
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_is_valid_utf8() {
+        let path = Path::new("testfile_is_valid_utf8.txt");
+        std::fs::write(path, "Sigur Rós\nMúm\n").unwrap();
+        assert!(is_valid_utf8(path).unwrap());
+
+        std::fs::write(path, b"Sigur R\xf3s\n").unwrap(); // Latin-1
+        assert!(!is_valid_utf8(path).unwrap());
+
+        std::fs::write(path, b"").unwrap();
+        assert!(is_valid_utf8(path).unwrap());
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_incremental_line_reader() {
+        let path = Path::new("testfile_incremental_line_reader.txt");
+        let text: String = (1..=10).map(|i| format!("line {}\n", i)).collect();
+        std::fs::write(path, text).unwrap();
+
+        let mut reader = IncrementalLineReader::new(io::BufReader::new(File::open(path).unwrap()));
+        let mut lines = Vec::new();
+        let mut counts = Vec::new();
+        loop {
+            let n = reader.next_n(4, |line| lines.push(line.to_string())).unwrap();
+            counts.push(n);
+            if n == 0 {
+                break;
+            }
+        }
+        assert_eq!(counts, vec![4, 4, 2, 0]);
+        assert_eq!(lines, (1..=10).map(|i| format!("line {}", i)).collect::<Vec<_>>());
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_scan_counts() {
+        let dir = Path::new("testdir_scan_counts");
+        std::fs::create_dir_all(dir.join("artist/album")).unwrap();
+        std::fs::create_dir_all(dir.join("empty")).unwrap();
+        for name in ["single.mp3", "artist/album/01.flac", "artist/album/02.flac", "artist/album/cover.jpg"] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+
+        let counts = scan_counts(dir, &["flac", "mp3"]).unwrap();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts[dir], 1);
+        assert_eq!(counts[&dir.join("artist/album")], 2);
+        // Directories without matching files of their own aren't listed
+        assert!(!counts.contains_key(&dir.join("artist")));
+        assert!(!counts.contains_key(&dir.join("empty")));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_for_each_reverse_chunk() {
+        let path = Path::new("testfile_for_each_reverse_chunk.bin");
+        let mut data = vec![0u8; 1000];
+        data[800..808].copy_from_slice(b"APETAGEX");
+        std::fs::write(path, &data).unwrap();
+
+        let mut found = None;
+        let mut offsets = Vec::new();
+        for_each_reverse_chunk(path, 128, |offset, chunk| {
+            offsets.push((offset, chunk.len()));
+            found = chunk.windows(8).position(|w| w == b"APETAGEX").map(|i| offset + i as u64);
+            found.is_some()
+        }).unwrap();
+        assert_eq!(found, Some(800));
+        assert_eq!(offsets, vec![(872, 128), (744, 128)]);
+
+        // Without a match every chunk is visited, ending with the short one at offset 0
+        offsets.clear();
+        for_each_reverse_chunk(path, 300, |offset, chunk| {
+            offsets.push((offset, chunk.len()));
+            false
+        }).unwrap();
+        assert_eq!(offsets, vec![(700, 300), (400, 300), (100, 300), (0, 100)]);
+
+        assert_eq!(for_each_reverse_chunk(path, 0, |_, _| false).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,19 @@
+//! # rust_cmus
+//!
+//! File, path and tag helpers for a cmus-style music player.
+//!
+//! The crate is Unix-only: it calls into libc and relies on Unix file
+//! semantics (inodes, `pread`, fd-based I/O) throughout, so nothing in it is
+//! gated on `cfg(unix)`.
+
+#[cfg(not(unix))]
+compile_error!("rust_cmus only supports Unix-like systems");
+
+pub mod cache;
+pub mod document;
+pub mod file;
+pub mod path;
+pub mod resume;
+pub mod tags;
+pub mod timestamp;
+pub mod watch;
@@ -1,6 +1,4 @@
-mod file;
-
 //Write main function
 fn main() {
     println!("Hello, world!");
-}
\ No newline at end of file
+}
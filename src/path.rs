@@ -0,0 +1,188 @@
+//! # Path Operations in Rust
+//!
+//! This module provides helpers for resolving and comparing paths.
+//!
+//! For a detailed explanation of the functions and their usage, see
+//! [the detailed explanation](../explanations/path.md).
+
+
+
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+
+
+
+
+pub fn safe_join(base: &Path, entry: &str) -> Option<PathBuf> {
+    let base = base.canonicalize().ok()?;
+    let joined = base.join(entry);
+
+    // A path that doesn't exist yet can't be canonicalized, so canonicalize its deepest
+    // existing ancestor instead; that still follows any symlink on the way
+    let mut resolved = None;
+    for ancestor in joined.ancestors() {
+        match ancestor.canonicalize() {
+            Ok(path) => {
+                resolved = Some((path, joined.strip_prefix(ancestor).ok()?));
+                break;
+            }
+            // It exists but can't be resolved: a dangling symlink could point anywhere
+            Err(_) if ancestor.symlink_metadata().is_ok() => return None,
+            Err(_) => {}
+        }
+    }
+    let (mut resolved, rest) = resolved?;
+
+    // The rest doesn't exist, so a `..` in it can't be resolved against the real tree
+    for component in rest.components() {
+        match component {
+            Component::Normal(name) => resolved.push(name),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+
+    if resolved.starts_with(&base) {
+        Some(resolved)
+    } else {
+        None
+    }
+}
+
+
+pub fn normalize_path(p: &Path) -> PathBuf {
+    // components() already collapses repeated separators and drops a trailing slash
+    let normalized: PathBuf = p.components()
+        .filter(|component| *component != Component::CurDir)
+        .collect();
+
+    if normalized.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        normalized
+    }
+}
+
+
+// Schemes handled by a network input instead of File::open
+const STREAM_SCHEMES: &[&str] = &["http", "https", "mms", "mmsh", "mmst", "rtsp", "rtmp", "icy"];
+
+pub fn is_stream_url(entry: &str) -> bool {
+    match entry.trim_start().split_once("://") {
+        Some((scheme, _)) => STREAM_SCHEMES.iter().any(|s| s.eq_ignore_ascii_case(scheme)),
+        None => false,
+    }
+}
+
+
+pub fn same_file(a: &Path, b: &Path) -> io::Result<bool> {
+    // metadata() follows symlinks, so a link and its target compare equal
+    let (a, b) = (std::fs::metadata(a)?, std::fs::metadata(b)?);
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    #[test]
+    fn test_safe_join() {
+        let base = Path::new("testdir_safe_join");
+        create_dir_all(base.join("album")).unwrap();
+        write(base.join("album/track.mp3"), b"").unwrap();
+
+        let joined = safe_join(base, "album/track.mp3").unwrap();
+        assert_eq!(joined, base.canonicalize().unwrap().join("album/track.mp3"));
+
+        let missing = safe_join(base, "album/../new.mp3").unwrap();
+        assert_eq!(missing, base.canonicalize().unwrap().join("new.mp3"));
+
+        remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_safe_join_traversal() {
+        let base = Path::new("testdir_safe_join_traversal");
+        create_dir_all(base).unwrap();
+
+        assert_eq!(safe_join(base, "../../etc/passwd"), None);
+        assert_eq!(safe_join(base, "/etc/passwd"), None);
+
+        remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_safe_join_symlink_escape() {
+        let base = Path::new("testdir_safe_join_symlink_escape");
+        create_dir_all(base.join("album")).unwrap();
+        std::os::unix::fs::symlink("/etc", base.join("link")).unwrap();
+        std::os::unix::fs::symlink("/nonexistent_safe_join_target", base.join("dangling")).unwrap();
+
+        // None of these exist yet, but each would be created outside the base
+        assert_eq!(safe_join(base, "link/newfile"), None);
+        assert_eq!(safe_join(base, "link/../newfile"), None);
+        assert_eq!(safe_join(base, "dangling"), None);
+        assert_eq!(safe_join(base, "dangling/newfile"), None);
+        // `..` below a directory that doesn't exist can't be checked
+        assert_eq!(safe_join(base, "missing/../newfile"), None);
+
+        let new = safe_join(base, "album/new/track.mp3").unwrap();
+        assert_eq!(new, base.canonicalize().unwrap().join("album/new/track.mp3"));
+
+        remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_normalize_path_cur_dir() {
+        assert_eq!(normalize_path(Path::new("./music/./track.mp3")), PathBuf::from("music/track.mp3"));
+        assert_eq!(normalize_path(Path::new("./")), PathBuf::from("."));
+    }
+
+    #[test]
+    fn test_normalize_path_duplicate_separators() {
+        assert_eq!(normalize_path(Path::new("./music//track.mp3")), PathBuf::from("music/track.mp3"));
+        assert_eq!(normalize_path(Path::new("/music//album///track.mp3")), PathBuf::from("/music/album/track.mp3"));
+    }
+
+    #[test]
+    fn test_normalize_path_trailing_slash() {
+        assert_eq!(normalize_path(Path::new("music/album/")), PathBuf::from("music/album"));
+        assert_eq!(normalize_path(Path::new("../music/")), PathBuf::from("../music"));
+    }
+
+    #[test]
+    fn test_is_stream_url() {
+        assert!(is_stream_url("http://radio.example.com:8000/stream"));
+        assert!(is_stream_url("HTTPS://radio.example.com/live.ogg"));
+        assert!(is_stream_url("mms://media.example.com/jazz"));
+        assert!(is_stream_url("rtsp://10.0.0.2/stream"));
+    }
+
+    #[test]
+    fn test_is_stream_url_plain_paths() {
+        assert!(!is_stream_url("/music/Kind of Blue/01 So What.flac"));
+        assert!(!is_stream_url("music/http:/not-a-url.mp3"));
+        assert!(!is_stream_url("file:///music/track.mp3"));
+        assert!(!is_stream_url(""));
+    }
+
+    #[test]
+    fn test_same_file() {
+        let dir = Path::new("testdir_same_file");
+        create_dir_all(dir).unwrap();
+        write(dir.join("track.mp3"), b"audio").unwrap();
+        write(dir.join("copy.mp3"), b"audio").unwrap();
+        std::fs::hard_link(dir.join("track.mp3"), dir.join("hardlink.mp3")).unwrap();
+        std::os::unix::fs::symlink("track.mp3", dir.join("symlink.mp3")).unwrap();
+
+        assert!(same_file(&dir.join("track.mp3"), &dir.join("hardlink.mp3")).unwrap());
+        assert!(same_file(&dir.join("symlink.mp3"), &dir.join("./track.mp3")).unwrap());
+        assert!(!same_file(&dir.join("track.mp3"), &dir.join("copy.mp3")).unwrap());
+        assert!(same_file(&dir.join("track.mp3"), &dir.join("missing.mp3")).is_err());
+
+        remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+//! # Resume State
+//!
+//! Saves and restores the last track, playback position and volume between runs.
+//!
+//! For a detailed explanation of the functions and their usage, see
+//! [the detailed explanation](../explanations/resume.md).
+
+
+
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::file::{buffer_for_each_field, cleanup_temp, write_atomic};
+
+
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResumeState {
+    pub track: PathBuf,
+    pub position: Duration,
+    pub volume: u8,
+}
+
+impl Default for ResumeState {
+    fn default() -> Self {
+        ResumeState {
+            track: PathBuf::new(),
+            position: Duration::ZERO,
+            volume: 100,
+        }
+    }
+}
+
+pub fn load_resume_state(path: &Path) -> io::Result<Option<ResumeState>> {
+    cleanup_temp(path)?;
+    let buf = match std::fs::read(path) {
+        Ok(buf) => buf,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    // Missing or unparsable fields keep their defaults
+    let mut state = ResumeState::default();
+    buffer_for_each_field(&buf, b'\n', |line| {
+        let Some(eq) = line.iter().position(|&b| b == b'=') else {
+            return false;
+        };
+        // The track is raw path bytes, so its value is taken exactly as written
+        let (key, value) = (&line[..eq], &line[eq + 1..]);
+        let number = || std::str::from_utf8(value).ok().map(str::trim);
+        match key.trim_ascii() {
+            b"track" => state.track = PathBuf::from(OsStr::from_bytes(value)),
+            b"position_ms" => {
+                if let Some(ms) = number().and_then(|value| value.parse().ok()) {
+                    state.position = Duration::from_millis(ms);
+                }
+            }
+            b"volume" => {
+                if let Some(volume) = number().and_then(|value| value.parse().ok()) {
+                    state.volume = volume;
+                }
+            }
+            _ => {}
+        }
+        false
+    });
+
+    Ok(Some(state))
+}
+
+pub fn save_resume_state(path: &Path, state: &ResumeState) -> io::Result<()> {
+    let track = state.track.as_os_str().as_bytes();
+    if track.contains(&b'\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "track path contains a newline"));
+    }
+
+    let mut contents = b"track=".to_vec();
+    contents.extend_from_slice(track);
+    contents.extend_from_slice(format!("\nposition_ms={}\nvolume={}\n", state.position.as_millis(), state.volume).as_bytes());
+    write_atomic(path, &contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{remove_file, write};
+
+    #[test]
+    fn test_resume_state_round_trip() {
+        let path = Path::new("testfile_resume_state_round_trip");
+        let state = ResumeState {
+            track: PathBuf::from("/music/Kind of Blue/01 So What.flac"),
+            position: Duration::from_millis(83_250),
+            volume: 72,
+        };
+
+        save_resume_state(path, &state).unwrap();
+        assert_eq!(load_resume_state(path).unwrap(), Some(state));
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_load_resume_state_missing_fields() {
+        let path = Path::new("testfile_load_resume_state_missing_fields");
+        write(path, b"track=/music/a.mp3\nvolume=loud\n").unwrap();
+
+        let state = load_resume_state(path).unwrap().unwrap();
+        assert_eq!(state.track, PathBuf::from("/music/a.mp3"));
+        assert_eq!(state.position, Duration::ZERO);
+        assert_eq!(state.volume, 100);
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_load_resume_state_absent() {
+        assert_eq!(load_resume_state(Path::new("non_existent_resume")).unwrap(), None);
+    }
+
+    #[test]
+    fn test_resume_state_raw_track_path() {
+        let path = Path::new("testfile_resume_state_raw_track_path");
+        // Latin-1 bytes, surrounding spaces and an '=' all survive the round trip
+        let track = PathBuf::from(OsStr::from_bytes(b" /music/Bj\xf6rk/a=b.flac "));
+        let state = ResumeState { track, position: Duration::from_millis(1_000), volume: 50 };
+        save_resume_state(path, &state).unwrap();
+        assert_eq!(load_resume_state(path).unwrap(), Some(state));
+
+        let state = ResumeState { track: PathBuf::from("/music/a\nvolume=0"), ..ResumeState::default() };
+        assert_eq!(save_resume_state(path, &state).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_load_resume_state_removes_stale_temp() {
+        let path = Path::new("testfile_load_resume_state_stale_temp");
+        let tmp = Path::new("testfile_load_resume_state_stale_temp.tmp");
+        write(path, b"volume=40\n").unwrap();
+        write(tmp, b"track=/music/half").unwrap();
+        let old = std::time::SystemTime::now() - Duration::from_secs(3600);
+        std::fs::File::options().write(true).open(tmp).unwrap().set_modified(old).unwrap();
+
+        assert_eq!(load_resume_state(path).unwrap().unwrap().volume, 40);
+        assert!(!tmp.exists());
+
+        remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,268 @@
+//! # Cover Art
+//!
+//! Extracts embedded album art from ID3v2 `APIC` frames, FLAC `PICTURE` blocks
+//! and Vorbis `METADATA_BLOCK_PICTURE` comments.
+//!
+//! For a detailed explanation of the functions and their usage, see
+//! [the detailed explanation](../../explanations/tags.md).
+
+
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use super::id3v2::for_each_id3v2_frame;
+use super::{be_u32, flac, format_from_extension, sniff_format, vorbis, Format};
+
+
+
+
+pub(crate) const PICTURE_FRONT_COVER: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoverArt {
+    pub mime: String,
+    pub data: Vec<u8>,
+}
+
+// The FLAC PICTURE block layout, also base64-encoded in Vorbis METADATA_BLOCK_PICTURE
+pub(crate) fn parse_picture(block: &[u8]) -> Option<(u32, CoverArt)> {
+    let picture_type = be_u32(block, 0)?;
+    let mime_len = be_u32(block, 4)? as usize;
+    let mime = block.get(8..8usize.checked_add(mime_len)?)?;
+    let mut pos = 8 + mime_len;
+    let desc_len = be_u32(block, pos)? as usize;
+    pos = pos.checked_add(4 + desc_len)?;
+    pos += 16; // Width, height, colour depth and palette size
+    let data_len = be_u32(block, pos)? as usize;
+    let data = block.get(pos + 4..(pos + 4).checked_add(data_len)?)?;
+
+    Some((picture_type, CoverArt {
+        mime: String::from_utf8_lossy(mime).into_owned(),
+        data: data.to_vec(),
+    }))
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let value = |c: u8| match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    };
+
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for c in text.bytes().filter(|c| !c.is_ascii_whitespace() && *c != b'=') {
+        acc = (acc << 6) | value(c)? as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+// The front cover if there is one, otherwise the first picture
+fn pick_cover(pictures: Vec<(u32, CoverArt)>) -> Option<CoverArt> {
+    let front = pictures.iter().position(|(picture_type, _)| *picture_type == PICTURE_FRONT_COVER);
+    pictures.into_iter().nth(front.unwrap_or(0)).map(|(_, cover)| cover)
+}
+
+fn parse_apic(frame: &[u8]) -> Option<(u32, CoverArt)> {
+    let encoding = *frame.first()?;
+    let mime_end = 1 + frame.get(1..)?.iter().position(|&b| b == 0)?;
+    let mime = &frame[1..mime_end];
+    let picture_type = *frame.get(mime_end + 1)? as u32;
+
+    // The description is NUL-terminated, with a two-byte NUL in the UTF-16 encodings
+    let desc = mime_end + 2;
+    let data_start = match encoding {
+        1 | 2 => {
+            let units = frame.get(desc..)?.chunks_exact(2).position(|unit| unit == [0, 0])?;
+            desc + units * 2 + 2
+        }
+        _ => desc + frame.get(desc..)?.iter().position(|&b| b == 0)? + 1,
+    };
+
+    let mime = match mime {
+        b"" => "image/".to_string(), // The spec's default
+        b"JPG" => "image/jpeg".to_string(),
+        b"PNG" => "image/png".to_string(),
+        mime => String::from_utf8_lossy(mime).into_owned(),
+    };
+    Some((picture_type, CoverArt { mime, data: frame[data_start..].to_vec() }))
+}
+
+fn read_id3v2_pictures(path: &Path) -> io::Result<Vec<(u32, CoverArt)>> {
+    let mut pictures = Vec::new();
+    for_each_id3v2_frame(path, |id, frame| {
+        if id == b"APIC" {
+            pictures.extend(parse_apic(frame));
+        }
+        false
+    })?;
+    Ok(pictures)
+}
+
+fn comment_pictures(comments: Option<Vec<(String, String)>>) -> Vec<(u32, CoverArt)> {
+    comments.unwrap_or_default()
+        .iter()
+        .filter(|(key, _)| key.eq_ignore_ascii_case("METADATA_BLOCK_PICTURE"))
+        .filter_map(|(_, value)| parse_picture(&base64_decode(value)?))
+        .collect()
+}
+
+pub fn extract_cover(path: &Path) -> io::Result<Option<CoverArt>> {
+    let mut header = Vec::with_capacity(8);
+    File::open(path)?.take(8).read_to_end(&mut header)?;
+
+    let pictures = match sniff_format(&header).or_else(|| format_from_extension(path)) {
+        Some(Format::Mp3) => read_id3v2_pictures(path)?,
+        Some(Format::Flac) => {
+            let blocks = flac::read_blocks(path, flac::BLOCK_PICTURE)?.unwrap_or_default();
+            let mut pictures: Vec<_> = blocks.iter().filter_map(|block| parse_picture(block)).collect();
+            if pictures.is_empty() {
+                pictures = comment_pictures(flac::read_flac_tags(path)?);
+            }
+            pictures
+        }
+        Some(Format::Ogg) => comment_pictures(vorbis::find_vorbis_comments(path)?),
+        // MP4 covr atoms aren't read yet
+        Some(Format::Mp4) | None => Vec::new(),
+    };
+
+    Ok(pick_cover(pictures))
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::tags::flac::tests::flac_block;
+    use crate::tags::id3v2::tests::id3v2_fixture;
+    use crate::tags::vorbis::tests::ogg_fixture;
+    use std::fs::{remove_file, write};
+
+    const JPEG: &[u8] = b"\xff\xd8\xff\xe0\x00\x10JFIF\x00 not much of a picture \xff\xd9";
+
+    pub(crate) fn picture_block(picture_type: u32, mime: &str, data: &[u8]) -> Vec<u8> {
+        let mut block = picture_type.to_be_bytes().to_vec();
+        block.extend_from_slice(&(mime.len() as u32).to_be_bytes());
+        block.extend_from_slice(mime.as_bytes());
+        block.extend_from_slice(&5u32.to_be_bytes());
+        block.extend_from_slice(b"Cover");
+        block.extend_from_slice(&[0u8; 16]);
+        block.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        block.extend_from_slice(data);
+        block
+    }
+
+    fn base64_encode(data: &[u8]) -> String {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut out = String::new();
+        for chunk in data.chunks(3) {
+            let n = chunk.iter().enumerate().fold(0u32, |acc, (i, &b)| acc | (b as u32) << (16 - 8 * i));
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+                } else {
+                    out.push('=');
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_extract_cover_id3v2() {
+        let path = Path::new("testfile_extract_cover.mp3");
+        let mut back = b"\x00image/png\x00\x04\x00".to_vec();
+        back.extend_from_slice(b"\x89PNG");
+        let mut front = b"\x01image/jpeg\x00\x03\xff\xfeC\x00o\x00v\x00e\x00r\x00\x00\x00".to_vec();
+        front.extend_from_slice(JPEG);
+        write(path, id3v2_fixture(&[(b"TIT2", b"\x00So What".to_vec()), (b"APIC", back), (b"APIC", front)])).unwrap();
+
+        let cover = extract_cover(path).unwrap().unwrap();
+        assert_eq!(cover.mime, "image/jpeg");
+        assert_eq!(cover.data, JPEG);
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_extract_cover_id3v2_grouped_frame() {
+        let path = Path::new("testfile_extract_cover_grouped.mp3");
+        let mut apic = b"\x00image/jpeg\x00\x03\x00".to_vec();
+        apic.extend_from_slice(JPEG);
+        // v2.3 frame with the grouping identity flag, so one group byte precedes the body
+        let mut frame = b"APIC".to_vec();
+        frame.extend_from_slice(&(apic.len() as u32 + 1).to_be_bytes());
+        frame.extend_from_slice(&[0x00, 0x20, 0x07]);
+        frame.extend_from_slice(&apic);
+        let size = frame.len() as u32;
+        let mut data = b"ID3\x03\x00\x00".to_vec();
+        data.extend((0..4).rev().map(|i| ((size >> (7 * i)) & 0x7f) as u8));
+        data.extend_from_slice(&frame);
+        write(path, data).unwrap();
+
+        let cover = extract_cover(path).unwrap().unwrap();
+        assert_eq!(cover.mime, "image/jpeg");
+        assert_eq!(cover.data, JPEG);
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_extract_cover_id3v2_hostile_size() {
+        // A bare header claiming a 256 MiB tag, then a frame header cut short
+        let path = Path::new("testfile_extract_cover_hostile.mp3");
+        write(path, b"ID3\x03\x00\x00\x7f\x7f\x7f\x7f").unwrap();
+        assert_eq!(extract_cover(path).unwrap(), None);
+
+        write(path, b"ID3\x03\x00\x00\x7f\x7f\x7f\x7fAPIC\xff\xff\xff\xff\x00\x00rest").unwrap();
+        assert_eq!(extract_cover(path).unwrap(), None);
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_extract_cover_flac() {
+        let path = Path::new("testfile_extract_cover.flac");
+        let mut data = b"fLaC".to_vec();
+        data.extend_from_slice(&flac_block(0, false, &[0u8; 34]));
+        data.extend_from_slice(&flac_block(flac::BLOCK_PICTURE, true, &picture_block(3, "image/jpeg", JPEG)));
+        write(path, data).unwrap();
+
+        let cover = extract_cover(path).unwrap().unwrap();
+        assert_eq!(cover.mime, "image/jpeg");
+        assert_eq!(cover.data, JPEG);
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_extract_cover_vorbis() {
+        let path = Path::new("testfile_extract_cover.ogg");
+        let comment = format!("METADATA_BLOCK_PICTURE={}", base64_encode(&picture_block(3, "image/jpeg", JPEG)));
+        write(path, ogg_fixture(&["TITLE=So What", &comment])).unwrap();
+
+        let cover = extract_cover(path).unwrap().unwrap();
+        assert_eq!(cover.mime, "image/jpeg");
+        assert_eq!(cover.data, JPEG);
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_extract_cover_none() {
+        let path = Path::new("testfile_extract_cover_none.flac");
+        write(path, crate::tags::flac::tests::flac_fixture(&["TITLE=So What"])).unwrap();
+        assert_eq!(extract_cover(path).unwrap(), None);
+        remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,171 @@
+//! # FLAC Tags
+//!
+//! Reads the metadata blocks of FLAC files, such as `VORBIS_COMMENT` and `PICTURE`.
+//!
+//! For a detailed explanation of the functions and their usage, see
+//! [the detailed explanation](../../explanations/tags.md).
+
+
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use super::cover::{parse_picture, PICTURE_FRONT_COVER};
+use super::vorbis::parse_comments;
+
+
+
+
+const BLOCK_VORBIS_COMMENT: u8 = 4;
+pub(crate) const BLOCK_PICTURE: u8 = 6;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlacPicture {
+    pub picture_type: u32, // 3 is the front cover, 4 the back cover; see the FLAC spec for the rest
+    pub mime: String,
+    pub data: Vec<u8>,
+}
+
+// Returns the contents of every block of `wanted` type, or None if this isn't a FLAC file
+pub(crate) fn read_blocks(path: &Path, wanted: u8) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let mut file = File::open(path)?;
+    let mut magic = [0u8; 4];
+    match file.read_exact(&mut magic) {
+        Ok(()) => {},
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    if &magic != b"fLaC" {
+        return Ok(None);
+    }
+
+    let mut blocks = Vec::new();
+    loop {
+        let mut header = [0u8; 4];
+        file.read_exact(&mut header)?;
+        let is_last = header[0] & 0x80 != 0;
+        let block_type = header[0] & 0x7f;
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+
+        if block_type == wanted {
+            let mut block = vec![0u8; len];
+            file.read_exact(&mut block)?;
+            blocks.push(block);
+        } else {
+            file.seek(SeekFrom::Current(len as i64))?;
+        }
+        if is_last {
+            // Audio frames follow
+            return Ok(Some(blocks));
+        }
+    }
+}
+
+pub fn read_flac_tags(path: &Path) -> io::Result<Option<Vec<(String, String)>>> {
+    let blocks = match read_blocks(path, BLOCK_VORBIS_COMMENT)? {
+        Some(blocks) => blocks,
+        None => return Ok(None),
+    };
+    match blocks.first() {
+        Some(block) => match parse_comments(block) {
+            Some(comments) => Ok(Some(comments)),
+            None => Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed FLAC comment block.")),
+        },
+        None => Ok(Some(Vec::new())), // There were no comments
+    }
+}
+
+
+pub fn read_flac_pictures(path: &Path) -> io::Result<Vec<FlacPicture>> {
+    let blocks = read_blocks(path, BLOCK_PICTURE)?.unwrap_or_default();
+    // A malformed block is skipped rather than losing the pictures around it
+    Ok(blocks.iter()
+        .filter_map(|block| parse_picture(block))
+        .map(|(picture_type, cover)| FlacPicture { picture_type, mime: cover.mime, data: cover.data })
+        .collect())
+}
+
+pub fn front_cover(pictures: &[FlacPicture]) -> Option<&FlacPicture> {
+    pictures.iter().find(|picture| picture.picture_type == PICTURE_FRONT_COVER)
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::tags::cover::tests::picture_block;
+    use crate::tags::vorbis::tests::comment_block;
+    use std::fs::{remove_file, write};
+
+    pub(crate) fn flac_block(block_type: u8, is_last: bool, data: &[u8]) -> Vec<u8> {
+        let len = (data.len() as u32).to_be_bytes();
+        let mut block = vec![block_type | if is_last { 0x80 } else { 0 }, len[1], len[2], len[3]];
+        block.extend_from_slice(data);
+        block
+    }
+
+    pub(crate) fn flac_fixture(comments: &[&str]) -> Vec<u8> {
+        let mut data = b"fLaC".to_vec();
+        data.extend_from_slice(&flac_block(0, false, &[0u8; 34])); // STREAMINFO
+        data.extend_from_slice(&flac_block(1, false, &[0u8; 16])); // PADDING
+        data.extend_from_slice(&flac_block(BLOCK_VORBIS_COMMENT, true, &comment_block("rust_cmus", comments)));
+        data.extend_from_slice(b"\xff\xf8 audio frames");
+        data
+    }
+
+    #[test]
+    fn test_read_flac_tags() {
+        let path = Path::new("testfile_read_flac_tags.flac");
+        write(path, flac_fixture(&["TITLE=Blue in Green", "ARTIST=Miles Davis", "ALBUM=Kind of Blue", "TRACKNUMBER=3"])).unwrap();
+
+        let tags = read_flac_tags(path).unwrap().unwrap();
+        assert_eq!(tags.len(), 4);
+        assert_eq!(tags[0], ("TITLE".to_string(), "Blue in Green".to_string()));
+        assert_eq!(tags[2], ("ALBUM".to_string(), "Kind of Blue".to_string()));
+        assert_eq!(tags[3], ("TRACKNUMBER".to_string(), "3".to_string()));
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_flac_tags_no_comments() {
+        let path = Path::new("testfile_read_flac_tags_no_comments.flac");
+        let mut data = b"fLaC".to_vec();
+        data.extend_from_slice(&flac_block(0, true, &[0u8; 34]));
+        write(path, data).unwrap();
+        assert_eq!(read_flac_tags(path).unwrap(), Some(Vec::new()));
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_flac_tags_not_flac() {
+        let path = Path::new("testfile_read_flac_tags_not_flac.ogg");
+        write(path, b"OggS").unwrap();
+        assert_eq!(read_flac_tags(path).unwrap(), None);
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_flac_pictures() {
+        let path = Path::new("testfile_read_flac_pictures.flac");
+        let mut data = b"fLaC".to_vec();
+        data.extend_from_slice(&flac_block(0, false, &[0u8; 34]));
+        data.extend_from_slice(&flac_block(BLOCK_PICTURE, false, &picture_block(4, "image/png", b"\x89PNG back")));
+        data.extend_from_slice(&flac_block(BLOCK_PICTURE, false, b"truncated"));
+        data.extend_from_slice(&flac_block(BLOCK_PICTURE, true, &picture_block(3, "image/jpeg", b"\xff\xd8 front")));
+        write(path, data).unwrap();
+
+        let pictures = read_flac_pictures(path).unwrap();
+        assert_eq!(pictures.iter().map(|picture| picture.picture_type).collect::<Vec<_>>(), vec![4, 3]);
+        assert_eq!(pictures[0].mime, "image/png");
+        let front = front_cover(&pictures).unwrap();
+        assert_eq!(front.mime, "image/jpeg");
+        assert_eq!(front.data, b"\xff\xd8 front");
+
+        assert_eq!(front_cover(&pictures[..1]), None);
+        write(path, flac_fixture(&["TITLE=So What"])).unwrap();
+        assert_eq!(read_flac_pictures(path).unwrap(), Vec::new());
+
+        remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,162 @@
+//! # Gapless Playback
+//!
+//! Reads the encoder delay and padding that gapless playback has to trim,
+//! from the LAME header of MP3 files and the `iTunSMPB` atom of MP4 files.
+//!
+//! For a detailed explanation of the functions and their usage, see
+//! [the detailed explanation](../../explanations/tags.md).
+
+
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use super::{be_u32, format_from_extension, mp4, sniff_format, synchsafe, Format};
+
+
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GaplessInfo {
+    pub delay: u32,   // Samples of encoder delay at the start
+    pub padding: u32, // Samples of padding at the end
+}
+
+// The Xing/Info header sits in the first audio frame, after the side information
+fn parse_lame(frame: &[u8]) -> Option<GaplessInfo> {
+    if frame.len() < 4 || frame[0] != 0xff || frame[1] & 0xe0 != 0xe0 {
+        return None;
+    }
+    let mpeg1 = (frame[1] >> 3) & 0x03 == 0x03;
+    let mono = (frame[3] >> 6) == 0x03;
+    let side_info = match (mpeg1, mono) {
+        (true, false) => 32,
+        (true, true) | (false, false) => 17,
+        (false, true) => 9,
+    };
+
+    let xing = 4 + side_info;
+    let magic = frame.get(xing..xing + 4)?;
+    if magic != b"Xing" && magic != b"Info" {
+        return None;
+    }
+    // Frame count, byte count, seek table and quality are each optional
+    let flags = be_u32(frame, xing + 4)?;
+    let mut pos = xing + 8;
+    for (flag, len) in [(0x01, 4), (0x02, 4), (0x04, 100), (0x08, 4)] {
+        if flags & flag != 0 {
+            pos += len;
+        }
+    }
+
+    // Encoder string (9), revision, lowpass, ReplayGain (8), flags and bitrate, then delay/padding
+    let lame = frame.get(pos..pos + 24)?;
+    if !lame.starts_with(b"LAME") && !lame.starts_with(b"Lavc") && !lame.starts_with(b"Lavf") {
+        return None;
+    }
+    let (a, b, c) = (lame[21] as u32, lame[22] as u32, lame[23] as u32);
+    Some(GaplessInfo {
+        delay: (a << 4) | (b >> 4),
+        padding: ((b & 0x0f) << 8) | c,
+    })
+}
+
+fn read_mp3_gapless(path: &Path) -> io::Result<Option<GaplessInfo>> {
+    let file = File::open(path)?;
+    let size = file.metadata()?.len();
+
+    // Skip an ID3v2 tag, and its footer if it has one
+    let mut offset = 0;
+    let mut id3 = [0u8; 10];
+    if size >= 10 {
+        file.read_exact_at(&mut id3, 0)?;
+        if &id3[..3] == b"ID3" {
+            offset = 10 + synchsafe(&id3[6..10]) as u64 + if id3[5] & 0x10 != 0 { 10 } else { 0 };
+        }
+    }
+    if offset >= size {
+        return Ok(None);
+    }
+
+    let mut frame = vec![0u8; (size - offset).min(512) as usize];
+    file.read_exact_at(&mut frame, offset)?;
+    Ok(parse_lame(&frame))
+}
+
+// " 00000000 00000840 000001CC 0000000000A2DE34 ...": zero, delay, padding, sample count
+fn parse_itunsmpb(value: &str) -> Option<GaplessInfo> {
+    let mut fields = value.split_whitespace().skip(1);
+    let delay = u32::from_str_radix(fields.next()?, 16).ok()?;
+    let padding = u32::from_str_radix(fields.next()?, 16).ok()?;
+    Some(GaplessInfo { delay, padding })
+}
+
+pub fn read_gapless_info(path: &Path) -> io::Result<Option<GaplessInfo>> {
+    let mut header = Vec::with_capacity(8);
+    File::open(path)?.take(8).read_to_end(&mut header)?;
+
+    match sniff_format(&header).or_else(|| format_from_extension(path)) {
+        Some(Format::Mp3) => read_mp3_gapless(path),
+        Some(Format::Mp4) => {
+            let value = mp4::read_freeform(path, "com.apple.iTunes", "iTunSMPB")?;
+            Ok(value.as_deref().and_then(parse_itunsmpb))
+        }
+        _ => Ok(None),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::mp4::tests::{data_box, m4a_fixture, mp4_box};
+    use std::fs::{remove_file, write};
+
+    fn mp3_fixture(delay: u32, padding: u32) -> Vec<u8> {
+        // MPEG-1 Layer III, 128 kbps, 44.1 kHz, joint stereo
+        let mut frame = vec![0xff, 0xfb, 0x90, 0x40];
+        frame.extend_from_slice(&[0u8; 32]); // Side information
+        frame.extend_from_slice(b"Info");
+        frame.extend_from_slice(&0x0fu32.to_be_bytes());
+        frame.extend_from_slice(&[0u8; 4 + 4 + 100 + 4]);
+
+        let mut lame = b"LAME3.100".to_vec();
+        lame.extend_from_slice(&[0u8; 12]);
+        lame.push((delay >> 4) as u8);
+        lame.push(((delay & 0x0f) << 4 | padding >> 8) as u8);
+        lame.push(padding as u8);
+        frame.extend_from_slice(&lame);
+        frame.resize(417, 0);
+
+        let mut data = b"ID3\x03\x00\x00\x00\x00\x00\x0a".to_vec();
+        data.extend_from_slice(&[0u8; 10]); // Padding-only tag
+        data.extend_from_slice(&frame);
+        data
+    }
+
+    #[test]
+    fn test_read_gapless_info_mp3() {
+        let path = Path::new("testfile_read_gapless_info.mp3");
+        write(path, mp3_fixture(576, 1692)).unwrap();
+        assert_eq!(read_gapless_info(path).unwrap(), Some(GaplessInfo { delay: 576, padding: 1692 }));
+
+        write(path, b"\xff\xfb\x90\x40 plain frame without an Info header").unwrap();
+        assert_eq!(read_gapless_info(path).unwrap(), None);
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_gapless_info_mp4() {
+        let path = Path::new("testfile_read_gapless_info.m4a");
+        let mut freeform = mp4_box(b"mean", b"\0\0\0\0com.apple.iTunes");
+        freeform.extend_from_slice(&mp4_box(b"name", b"\0\0\0\0iTunSMPB"));
+        freeform.extend_from_slice(&data_box(1, b" 00000000 00000840 000001CC 0000000000A2DE34 00000000"));
+        write(path, m4a_fixture(&[mp4_box(b"----", &freeform)])).unwrap();
+
+        assert_eq!(read_gapless_info(path).unwrap(), Some(GaplessInfo { delay: 0x840, padding: 0x1cc }));
+
+        remove_file(path).unwrap();
+    }
+}
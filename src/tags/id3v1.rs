@@ -0,0 +1,103 @@
+//! # ID3v1 Tags
+//!
+//! Reads the fixed 128-byte ID3v1 (and v1.1) tag at the end of MP3 files.
+//!
+//! For a detailed explanation of the functions and their usage, see
+//! [the detailed explanation](../../explanations/tags.md).
+
+
+
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+
+
+
+const TAG_SIZE: u64 = 128;
+
+// ID3v1 text is Latin-1, which maps byte-for-byte onto the first 256 code points
+fn latin1_field(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    bytes[..end].iter().map(|&b| b as char).collect::<String>().trim_end().to_string()
+}
+
+pub fn read_id3v1(path: &Path) -> io::Result<Option<Vec<(String, String)>>> {
+    let file = File::open(path)?;
+    let size = file.metadata()?.len();
+    if size < TAG_SIZE {
+        return Ok(None);
+    }
+
+    let mut tag = [0u8; TAG_SIZE as usize];
+    file.read_exact_at(&mut tag, size - TAG_SIZE)?;
+    if &tag[..3] != b"TAG" {
+        return Ok(None);
+    }
+
+    let mut tags = Vec::new();
+    let fields = [
+        ("TITLE", &tag[3..33]),
+        ("ARTIST", &tag[33..63]),
+        ("ALBUM", &tag[63..93]),
+        ("DATE", &tag[93..97]),
+    ];
+    for (key, bytes) in fields {
+        let value = latin1_field(bytes);
+        if !value.is_empty() {
+            tags.push((key.to_string(), value));
+        }
+    }
+    // ID3v1.1 steals the last two comment bytes for a zero marker and a track number
+    if tag[125] == 0 && tag[126] != 0 {
+        tags.push(("TRACKNUMBER".to_string(), tag[126].to_string()));
+    }
+
+    Ok(Some(tags))
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::fs::{remove_file, write};
+
+    pub(crate) fn mp3_fixture(title: &str, artist: &str, track: u8) -> Vec<u8> {
+        let mut tag = vec![0u8; TAG_SIZE as usize];
+        tag[..3].copy_from_slice(b"TAG");
+        tag[3..3 + title.len()].copy_from_slice(title.as_bytes());
+        tag[33..33 + artist.len()].copy_from_slice(artist.as_bytes());
+        tag[93..97].copy_from_slice(b"1959");
+        tag[126] = track;
+        tag[127] = 8; // Genre: Jazz
+
+        let mut data = b"\xff\xfb\x90\x00 audio frames".to_vec();
+        data.extend_from_slice(&tag);
+        data
+    }
+
+    #[test]
+    fn test_read_id3v1() {
+        let path = Path::new("testfile_read_id3v1.mp3");
+        write(path, mp3_fixture("All Blues", "Miles Davis", 4)).unwrap();
+
+        let pair = |k: &str, v: &str| (k.to_string(), v.to_string());
+        assert_eq!(read_id3v1(path).unwrap().unwrap(), vec![
+            pair("TITLE", "All Blues"),
+            pair("ARTIST", "Miles Davis"),
+            pair("DATE", "1959"),
+            pair("TRACKNUMBER", "4"),
+        ]);
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_id3v1_no_tag() {
+        let path = Path::new("testfile_read_id3v1_no_tag.mp3");
+        write(path, vec![0u8; 200]).unwrap();
+        assert_eq!(read_id3v1(path).unwrap(), None);
+        remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,178 @@
+//! # ID3v2
+//!
+//! Walks the frames of an ID3v2.3 or v2.4 tag at the start of an MP3 file, and
+//! reads its `TXXX` user-defined text frames.
+//!
+//! For a detailed explanation of the functions and their usage, see
+//! [the detailed explanation](../../explanations/tags.md).
+
+
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use super::{be_u32, synchsafe};
+
+
+
+
+pub(crate) fn for_each_id3v2_frame<F>(path: &Path, mut cb: F) -> io::Result<()>
+where
+    F: FnMut(&[u8], &[u8]) -> bool,
+{
+    let mut file = File::open(path)?;
+    let mut header = [0u8; 10];
+    match file.read_exact(&mut header) {
+        Ok(()) => {},
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+        Err(e) => return Err(e),
+    }
+    let (major, flags) = (header[3], header[5]);
+    // v2.2 uses three-letter frame ids, and tag-wide unsynchronisation would need undoing first
+    if &header[..3] != b"ID3" || !(3..=4).contains(&major) || flags & 0x80 != 0 {
+        return Ok(());
+    }
+
+    // The size is only a claim; read what the file actually has instead of allocating it up front
+    let mut tag = Vec::new();
+    file.take(synchsafe(&header[6..10]) as u64).read_to_end(&mut tag)?;
+
+    let mut pos = 0;
+    if flags & 0x40 != 0 {
+        // Extended header: v2.4 counts its own size field, v2.3 doesn't
+        let size = match major {
+            4 => synchsafe(tag.get(..4).unwrap_or(&[])) as usize,
+            _ => be_u32(&tag, 0).map_or(0, |size| size as usize + 4),
+        };
+        pos = size;
+    }
+
+    while pos + 10 <= tag.len() && tag[pos] != 0 {
+        let id = &tag[pos..pos + 4];
+        let size = match major {
+            4 => synchsafe(&tag[pos + 4..pos + 8]),
+            _ => be_u32(&tag, pos + 4).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "Truncated ID3v2 frame header.")
+            })?,
+        } as usize;
+        let format_flags = tag[pos + 9];
+        let Some(mut frame) = tag.get(pos + 10..pos + 10 + size) else {
+            break;
+        };
+        pos += 10 + size;
+
+        // Compressed, encrypted or unsynchronised frames are skipped
+        let skip = if major == 4 { 0x0e } else { 0xc0 };
+        if format_flags & skip != 0 {
+            continue;
+        }
+        // A group id byte, then in v2.4 a data length indicator, come before the frame body
+        let grouping = if major == 4 { 0x40 } else { 0x20 };
+        let mut extra = if format_flags & grouping != 0 { 1 } else { 0 };
+        if major == 4 && format_flags & 0x01 != 0 {
+            extra += 4;
+        }
+        frame = frame.get(extra..).unwrap_or(&[]);
+        if cb(id, frame) {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// Encodings 1 and 2 are UTF-16, with a byte order mark or always big-endian
+fn decode_text(encoding: u8, bytes: &[u8]) -> String {
+    match encoding {
+        0 => bytes.iter().map(|&b| b as char).collect(), // ISO-8859-1
+        1 | 2 => {
+            let (little_endian, bytes) = match bytes {
+                [0xff, 0xfe, rest @ ..] if encoding == 1 => (true, rest),
+                [0xfe, 0xff, rest @ ..] if encoding == 1 => (false, rest),
+                _ => (false, bytes),
+            };
+            let units: Vec<u16> = bytes
+                .chunks_exact(2)
+                .map(|unit| match little_endian {
+                    true => u16::from_le_bytes([unit[0], unit[1]]),
+                    false => u16::from_be_bytes([unit[0], unit[1]]),
+                })
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::from_utf8_lossy(bytes).into_owned(),
+    }
+}
+
+// An encoding byte, then a description and a value split by a NUL, two bytes wide in UTF-16
+fn parse_txxx(frame: &[u8]) -> Option<(String, String)> {
+    let (&encoding, text) = frame.split_first()?;
+    let (desc, value) = match encoding {
+        1 | 2 => {
+            let units = text.chunks_exact(2).position(|unit| unit == [0, 0])?;
+            (&text[..units * 2], &text[units * 2 + 2..])
+        }
+        _ => {
+            let end = text.iter().position(|&b| b == 0)?;
+            (&text[..end], &text[end + 1..])
+        }
+    };
+    let value = decode_text(encoding, value);
+    Some((decode_text(encoding, desc), value.trim_end_matches('\0').to_string()))
+}
+
+pub fn read_id3v2_txxx(path: &Path) -> io::Result<Vec<(String, String)>> {
+    let mut fields = Vec::new();
+    for_each_id3v2_frame(path, |id, frame| {
+        if id == b"TXXX" {
+            fields.extend(parse_txxx(frame));
+        }
+        false
+    })?;
+    Ok(fields)
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::fs::{remove_file, write};
+
+    pub(crate) fn id3v2_fixture(frames: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let mut tag = Vec::new();
+        for (id, body) in frames {
+            tag.extend_from_slice(*id);
+            tag.extend_from_slice(&(body.len() as u32).to_be_bytes());
+            tag.extend_from_slice(&[0, 0]);
+            tag.extend_from_slice(body);
+        }
+        tag.extend_from_slice(&[0u8; 32]); // Padding
+
+        let size = tag.len() as u32;
+        let mut data = b"ID3\x03\x00\x00".to_vec();
+        data.extend((0..4).rev().map(|i| ((size >> (7 * i)) & 0x7f) as u8));
+        data.extend_from_slice(&tag);
+        data.extend_from_slice(b"\xff\xfb\x90\x00 audio frames");
+        data
+    }
+
+    #[test]
+    fn test_read_id3v2_txxx() {
+        let path = Path::new("testfile_read_id3v2_txxx.mp3");
+        write(path, id3v2_fixture(&[
+            (b"TIT2", b"\x00So What".to_vec()),
+            (b"TXXX", b"\x00REPLAYGAIN_TRACK_GAIN\x00-6.48 dB".to_vec()),
+            (b"TXXX", b"\x01\xff\xfeP\x00e\x00a\x00k\x00\x00\x00\xff\xfe0\x00.\x009\x00\x00\x00".to_vec()),
+            (b"TXXX", b"\x03no separator".to_vec()),
+        ])).unwrap();
+        assert_eq!(read_id3v2_txxx(path).unwrap(), vec![
+            ("REPLAYGAIN_TRACK_GAIN".to_string(), "-6.48 dB".to_string()),
+            ("Peak".to_string(), "0.9".to_string()),
+        ]);
+
+        write(path, b"\xff\xfb\x90\x00 no tag").unwrap();
+        assert_eq!(read_id3v2_txxx(path).unwrap(), vec![]);
+
+        remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,186 @@
+//! # Tags
+//!
+//! Per-format tag readers, and `read_tags` to pick the right one for a file.
+//!
+//! For a detailed explanation of the functions and their usage, see
+//! [the detailed explanation](../../explanations/tags.md).
+
+pub mod cover;
+pub mod flac;
+pub mod gapless;
+pub mod id3v1;
+pub mod id3v2;
+pub mod mp4;
+pub mod replaygain;
+pub mod vorbis;
+
+
+
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Mp3,
+    Ogg,
+    Flac,
+    Mp4,
+}
+
+fn sniff_format(header: &[u8]) -> Option<Format> {
+    if header.starts_with(b"fLaC") {
+        Some(Format::Flac)
+    } else if header.starts_with(b"OggS") {
+        Some(Format::Ogg)
+    } else if header.get(4..8) == Some(b"ftyp") {
+        Some(Format::Mp4)
+    } else if header.starts_with(b"ID3") || (header.len() >= 2 && header[0] == 0xff && header[1] & 0xe0 == 0xe0) {
+        Some(Format::Mp3) // ID3v2 header or an MPEG frame sync
+    } else {
+        None
+    }
+}
+
+// ID3v2 sizes use 7 bits per byte so they never contain a frame sync
+pub(crate) fn synchsafe(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |acc, &b| (acc << 7) | (b & 0x7f) as u32)
+}
+
+// Tag formats other than Vorbis comments store their integers big-endian
+pub(crate) fn be_u32(data: &[u8], pos: usize) -> Option<u32> {
+    let bytes = data.get(pos..pos.checked_add(4)?)?;
+    Some(u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn format_from_extension(path: &Path) -> Option<Format> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    match ext.as_str() {
+        "mp3" => Some(Format::Mp3),
+        "ogg" | "oga" => Some(Format::Ogg),
+        "flac" => Some(Format::Flac),
+        "m4a" | "m4b" | "mp4" | "aac" => Some(Format::Mp4),
+        _ => None,
+    }
+}
+
+// Spellings taggers use for the same field, and the key they all map to
+const TAG_SYNONYMS: &[(&str, &str)] = &[
+    ("album artist", "albumartist"),
+    ("album_artist", "albumartist"),
+    ("albumartist", "albumartist"),
+    ("artist", "artist"),
+    ("album", "album"),
+    ("title", "title"),
+    ("date", "date"),
+    ("year", "date"),
+    ("genre", "genre"),
+    ("track", "tracknumber"),
+    ("tracknumber", "tracknumber"),
+    ("disc", "discnumber"),
+    ("discnumber", "discnumber"),
+    ("disknumber", "discnumber"),
+    ("comment", "comment"),
+];
+
+pub fn canonical_tag_key(key: &str) -> Cow<'static, str> {
+    let key = key.to_ascii_lowercase();
+    match TAG_SYNONYMS.iter().find(|(synonym, _)| *synonym == key) {
+        Some((_, canonical)) => Cow::Borrowed(canonical),
+        None => Cow::Owned(key),
+    }
+}
+
+pub fn read_tags(path: &Path) -> io::Result<Vec<(String, String)>> {
+    let mut header = Vec::with_capacity(8);
+    File::open(path)?.take(8).read_to_end(&mut header)?;
+
+    // The content wins over the extension, which may be wrong
+    let format = match sniff_format(&header).or_else(|| format_from_extension(path)) {
+        Some(format) => format,
+        None => return Ok(Vec::new()),
+    };
+    let tags = match format {
+        Format::Mp3 => id3v1::read_id3v1(path)?,
+        Format::Ogg => vorbis::find_vorbis_comments(path)?,
+        Format::Flac => flac::read_flac_tags(path)?,
+        Format::Mp4 => mp4::read_mp4_tags(path)?,
+    };
+
+    Ok(tags.unwrap_or_default()
+        .into_iter()
+        .map(|(key, value)| (canonical_tag_key(&key).into_owned(), value))
+        .collect())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{remove_file, write};
+
+    #[test]
+    fn test_read_tags() {
+        let mp3 = Path::new("testfile_read_tags.mp3");
+        let flac = Path::new("testfile_read_tags.flac");
+        let ogg = Path::new("testfile_read_tags.ogg");
+        write(mp3, id3v1::tests::mp3_fixture("So What", "Miles Davis", 1)).unwrap();
+        write(flac, flac::tests::flac_fixture(&["TITLE=Blue in Green", "artist=Bill Evans"])).unwrap();
+        write(ogg, vorbis::tests::ogg_fixture(&["TITLE=Flamenco Sketches"])).unwrap();
+
+        let title = |tags: &[(String, String)]| tags.iter().find(|(k, _)| k == "title").map(|(_, v)| v.clone());
+        let mp3_tags = read_tags(mp3).unwrap();
+        let flac_tags = read_tags(flac).unwrap();
+        let ogg_tags = read_tags(ogg).unwrap();
+        assert_eq!(title(&mp3_tags), Some("So What".to_string()));
+        assert_eq!(title(&flac_tags), Some("Blue in Green".to_string()));
+        assert_eq!(title(&ogg_tags), Some("Flamenco Sketches".to_string()));
+        assert!(flac_tags.contains(&("artist".to_string(), "Bill Evans".to_string())));
+
+        remove_file(mp3).unwrap();
+        remove_file(flac).unwrap();
+        remove_file(ogg).unwrap();
+    }
+
+    #[test]
+    fn test_read_tags_sniffs_content() {
+        // A FLAC file with the wrong extension is still read as FLAC
+        let path = Path::new("testfile_read_tags_sniff.mp3");
+        write(path, flac::tests::flac_fixture(&["TITLE=Blue in Green"])).unwrap();
+        assert_eq!(read_tags(path).unwrap(), vec![("title".to_string(), "Blue in Green".to_string())]);
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_tags_unknown_format() {
+        let path = Path::new("testfile_read_tags_unknown.txt");
+        write(path, b"just some text").unwrap();
+        assert_eq!(read_tags(path).unwrap(), Vec::new());
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_canonical_tag_key() {
+        assert_eq!(canonical_tag_key("ARTIST"), "artist");
+        assert_eq!(canonical_tag_key("Artist"), "artist");
+        assert_eq!(canonical_tag_key("ALBUMARTIST"), "albumartist");
+        assert_eq!(canonical_tag_key("Album Artist"), "albumartist");
+        assert_eq!(canonical_tag_key("album_artist"), "albumartist");
+        assert_eq!(canonical_tag_key("TRACK"), "tracknumber");
+        assert_eq!(canonical_tag_key("Year"), "date");
+        assert!(matches!(canonical_tag_key("TITLE"), Cow::Borrowed("title")));
+        // Unknown keys are kept, just lowercased
+        assert_eq!(canonical_tag_key("REPLAYGAIN_TRACK_GAIN"), "replaygain_track_gain");
+    }
+
+    #[test]
+    fn test_be_u32() {
+        assert_eq!(be_u32(b"\x00\x00\x01\x02\x03", 1), Some(0x0001_0203));
+        assert_eq!(be_u32(b"\x00\x00\x01", 0), None);
+        assert_eq!(be_u32(b"\x00\x00\x01\x02", usize::MAX - 1), None);
+    }
+}
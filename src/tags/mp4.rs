@@ -0,0 +1,271 @@
+//! # MP4 Tags
+//!
+//! Reads iTunes-style metadata (`moov > udta > meta > ilst`) from M4A/MP4 files.
+//!
+//! For a detailed explanation of the functions and their usage, see
+//! [the detailed explanation](../../explanations/tags.md).
+
+
+
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+
+
+
+const DATA_TYPE_UTF8: u32 = 1;
+
+// ilst atoms we understand, and the key they are reported under
+const ATOM_KEYS: &[(&[u8; 4], &str)] = &[
+    (b"\xa9nam", "TITLE"),
+    (b"\xa9ART", "ARTIST"),
+    (b"\xa9alb", "ALBUM"),
+    (b"aART", "ALBUMARTIST"),
+    (b"\xa9day", "DATE"),
+    (b"\xa9gen", "GENRE"),
+    (b"trkn", "TRACKNUMBER"),
+];
+
+struct Mp4Box {
+    kind: [u8; 4],
+    body: u64,
+    end: u64,
+}
+
+fn read_box(file: &File, pos: u64, limit: u64) -> io::Result<Option<Mp4Box>> {
+    // Sizes come from the file, so a malformed one mustn't overflow the offset arithmetic
+    let overrun = || io::Error::new(io::ErrorKind::InvalidData, "MP4 box overruns its parent.");
+    match pos.checked_add(8) {
+        Some(header_end) if header_end <= limit => {}
+        _ => return Ok(None),
+    }
+    let mut header = [0u8; 8];
+    file.read_exact_at(&mut header, pos)?;
+    let size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+    let kind = [header[4], header[5], header[6], header[7]];
+
+    let (body, end) = match size {
+        0 => (pos + 8, Some(limit)), // Extends to the end of the parent
+        1 => {
+            let mut large = [0u8; 8];
+            file.read_exact_at(&mut large, pos + 8)?;
+            (pos.checked_add(16).ok_or_else(overrun)?, pos.checked_add(u64::from_be_bytes(large)))
+        }
+        _ => (pos + 8, pos.checked_add(size)),
+    };
+    let end = end.ok_or_else(overrun)?;
+    if end < body || end > limit {
+        return Err(overrun());
+    }
+
+    Ok(Some(Mp4Box { kind, body, end }))
+}
+
+fn find_child(file: &File, start: u64, end: u64, kind: &[u8; 4]) -> io::Result<Option<Mp4Box>> {
+    let mut pos = start;
+    while let Some(mp4_box) = read_box(file, pos, end)? {
+        if &mp4_box.kind == kind {
+            return Ok(Some(mp4_box));
+        }
+        pos = mp4_box.end;
+    }
+    Ok(None)
+}
+
+fn read_data_value(file: &File, atom: &Mp4Box, key: &str) -> io::Result<Option<String>> {
+    let data = match find_child(file, atom.body, atom.end, b"data")? {
+        Some(data) if data.end - data.body >= 8 => data,
+        _ => return Ok(None),
+    };
+    // A data box starts with a 4-byte type indicator and a 4-byte locale
+    let mut payload = vec![0u8; (data.end - data.body) as usize];
+    file.read_exact_at(&mut payload, data.body)?;
+    let data_type = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+    let value = &payload[8..];
+
+    if key == "TRACKNUMBER" {
+        // Binary: 2 bytes padding, 2 bytes track number, 2 bytes total
+        return Ok(value.get(2..4).map(|n| u16::from_be_bytes([n[0], n[1]]).to_string()));
+    }
+    if data_type != DATA_TYPE_UTF8 {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8_lossy(value).into_owned()))
+}
+
+pub fn read_mp4_tags(path: &Path) -> io::Result<Option<Vec<(String, String)>>> {
+    let file = File::open(path)?;
+    let size = file.metadata()?.len();
+
+    match read_box(&file, 0, size) {
+        Ok(Some(ftyp)) if &ftyp.kind == b"ftyp" => {},
+        _ => return Ok(None), // Including boxes too short or malformed to be MP4
+    }
+
+    let mut tags = Vec::new();
+    let ilst = match find_ilst(&file, size)? {
+        Some(ilst) => ilst,
+        None => return Ok(Some(tags)),
+    };
+
+    let mut pos = ilst.body;
+    while let Some(atom) = read_box(&file, pos, ilst.end)? {
+        if let Some((_, key)) = ATOM_KEYS.iter().find(|(kind, _)| **kind == atom.kind) {
+            if let Some(value) = read_data_value(&file, &atom, key)? {
+                tags.push((key.to_string(), value));
+            }
+        }
+        pos = atom.end;
+    }
+
+    Ok(Some(tags))
+}
+
+fn find_ilst(file: &File, size: u64) -> io::Result<Option<Mp4Box>> {
+    let moov = match find_child(file, 0, size, b"moov")? {
+        Some(moov) => moov,
+        None => return Ok(None),
+    };
+    let udta = match find_child(file, moov.body, moov.end, b"udta")? {
+        Some(udta) => udta,
+        None => return Ok(None),
+    };
+    let meta = match find_child(file, udta.body, udta.end, b"meta")? {
+        Some(meta) => meta,
+        None => return Ok(None),
+    };
+
+    // meta is normally a full box with 4 bytes of version/flags, but QuickTime omits them
+    let mut children = meta.body;
+    if meta.end - meta.body >= 8 {
+        let mut probe = [0u8; 4];
+        file.read_exact_at(&mut probe, meta.body + 4)?;
+        if &probe != b"hdlr" {
+            children += 4;
+        }
+    }
+    find_child(file, children, meta.end, b"ilst")
+}
+
+// The string body of a mean or name box, after its 4 bytes of version/flags
+fn read_freeform_label(file: &File, atom: &Mp4Box, kind: &[u8; 4]) -> io::Result<Option<Vec<u8>>> {
+    match find_child(file, atom.body, atom.end, kind)? {
+        Some(label) if label.end - label.body >= 4 => {
+            let mut text = vec![0u8; (label.end - label.body - 4) as usize];
+            file.read_exact_at(&mut text, label.body + 4)?;
+            Ok(Some(text))
+        }
+        _ => Ok(None),
+    }
+}
+
+// Reads a `----` atom, which carries its own namespace (mean) and key (name)
+pub(crate) fn read_freeform(path: &Path, mean: &str, name: &str) -> io::Result<Option<String>> {
+    let file = File::open(path)?;
+    let size = file.metadata()?.len();
+    match read_box(&file, 0, size) {
+        Ok(Some(ftyp)) if &ftyp.kind == b"ftyp" => {},
+        _ => return Ok(None),
+    }
+    let ilst = match find_ilst(&file, size)? {
+        Some(ilst) => ilst,
+        None => return Ok(None),
+    };
+
+    let mut pos = ilst.body;
+    while let Some(atom) = read_box(&file, pos, ilst.end)? {
+        pos = atom.end;
+        if &atom.kind != b"----" {
+            continue;
+        }
+        if read_freeform_label(&file, &atom, b"mean")?.as_deref() == Some(mean.as_bytes())
+            && read_freeform_label(&file, &atom, b"name")?.as_deref() == Some(name.as_bytes())
+        {
+            return read_data_value(&file, &atom, name);
+        }
+    }
+    Ok(None)
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::fs::{remove_file, write};
+
+    pub(crate) fn mp4_box(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut data = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(kind);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    pub(crate) fn data_box(data_type: u32, value: &[u8]) -> Vec<u8> {
+        let mut payload = data_type.to_be_bytes().to_vec();
+        payload.extend_from_slice(&[0u8; 4]); // Locale
+        payload.extend_from_slice(value);
+        mp4_box(b"data", &payload)
+    }
+
+    pub(crate) fn m4a_fixture(atoms: &[Vec<u8>]) -> Vec<u8> {
+        let ilst = mp4_box(b"ilst", &atoms.concat());
+        let mut meta = vec![0u8; 4]; // Version and flags
+        meta.extend_from_slice(&mp4_box(b"hdlr", &[0u8; 25]));
+        meta.extend_from_slice(&ilst);
+        let udta = mp4_box(b"udta", &mp4_box(b"meta", &meta));
+        let moov = mp4_box(b"moov", &[mp4_box(b"mvhd", &[0u8; 100]), udta].concat());
+
+        let mut data = mp4_box(b"ftyp", b"M4A \0\0\0\0M4A mp42isom");
+        data.extend_from_slice(&moov);
+        data.extend_from_slice(&mp4_box(b"mdat", b"audio"));
+        data
+    }
+
+    #[test]
+    fn test_read_mp4_tags() {
+        let path = Path::new("testfile_read_mp4_tags.m4a");
+        write(path, m4a_fixture(&[
+            mp4_box(b"\xa9nam", &data_box(1, b"Freddie Freeloader")),
+            mp4_box(b"\xa9ART", &data_box(1, b"Miles Davis")),
+            mp4_box(b"\xa9alb", &data_box(1, b"Kind of Blue")),
+            mp4_box(b"trkn", &data_box(0, &[0, 0, 0, 2, 0, 5, 0, 0])),
+            mp4_box(b"covr", &data_box(13, b"\xff\xd8\xff")),
+        ])).unwrap();
+
+        let tags = read_mp4_tags(path).unwrap().unwrap();
+        let pair = |k: &str, v: &str| (k.to_string(), v.to_string());
+        assert_eq!(tags, vec![
+            pair("TITLE", "Freddie Freeloader"),
+            pair("ARTIST", "Miles Davis"),
+            pair("ALBUM", "Kind of Blue"),
+            pair("TRACKNUMBER", "2"),
+        ]);
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_mp4_tags_not_mp4() {
+        let path = Path::new("testfile_read_mp4_tags_not_mp4.flac");
+        write(path, b"fLaC\0\0\0\x22").unwrap();
+        assert_eq!(read_mp4_tags(path).unwrap(), None);
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_mp4_tags_huge_box_size() {
+        let path = Path::new("testfile_read_mp4_tags_huge_box_size.m4a");
+        let mut data = mp4_box(b"ftyp", b"M4A \0\0\0\0M4A mp42isom");
+        data.extend_from_slice(&1u32.to_be_bytes());
+        data.extend_from_slice(b"moov");
+        data.extend_from_slice(&u64::MAX.to_be_bytes()); // 64-bit largesize
+        data.extend_from_slice(&[0u8; 32]);
+        write(path, data).unwrap();
+
+        assert_eq!(read_mp4_tags(path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        remove_file(path).unwrap();
+    }
+}
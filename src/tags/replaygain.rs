@@ -0,0 +1,109 @@
+//! # ReplayGain
+//!
+//! Reads the `REPLAYGAIN_TRACK_GAIN` and `REPLAYGAIN_TRACK_PEAK` tags used for
+//! volume normalization.
+//!
+//! For a detailed explanation of the functions and their usage, see
+//! [the detailed explanation](../../explanations/tags.md).
+
+
+
+use std::io;
+use std::path::Path;
+use super::id3v2::read_id3v2_txxx;
+use super::{canonical_tag_key, read_tags};
+
+
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+}
+
+// "-3.21 dB", "+1.5 dB" or a bare number; taggers sometimes write U+2212 for the minus sign
+fn parse_gain(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let value = match value.len().checked_sub(2) {
+        Some(i) if value.is_char_boundary(i) && value[i..].eq_ignore_ascii_case("db") => &value[..i],
+        _ => value,
+    };
+    value.trim().replace('\u{2212}', "-").parse().ok()
+}
+
+pub fn read_replaygain(path: &Path) -> io::Result<Option<ReplayGain>> {
+    let mut gain = ReplayGain { track_gain: None, track_peak: None };
+    let mut found = false;
+
+    // MP3 keeps ReplayGain in ID3v2 TXXX frames, which read_tags doesn't see
+    let txxx = read_id3v2_txxx(path)?
+        .into_iter()
+        .map(|(key, value)| (canonical_tag_key(&key).into_owned(), value));
+    for (key, value) in txxx.chain(read_tags(path)?) {
+        match key.as_str() {
+            "replaygain_track_gain" => gain.track_gain = parse_gain(&value),
+            "replaygain_track_peak" => gain.track_peak = value.trim().parse().ok(),
+            _ => continue,
+        }
+        found = true;
+    }
+
+    Ok(if found { Some(gain) } else { None })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tags::flac::tests::flac_fixture;
+    use crate::tags::id3v2::tests::id3v2_fixture;
+    use std::fs::{remove_file, write};
+
+    #[test]
+    fn test_parse_gain() {
+        assert_eq!(parse_gain("-3.21 dB"), Some(-3.21));
+        assert_eq!(parse_gain("\u{2212}3.21 dB"), Some(-3.21));
+        assert_eq!(parse_gain("+1.50dB"), Some(1.5));
+        assert_eq!(parse_gain("0.42"), Some(0.42));
+        assert_eq!(parse_gain("loud"), None);
+    }
+
+    #[test]
+    fn test_read_replaygain() {
+        let path = Path::new("testfile_read_replaygain.flac");
+        write(path, flac_fixture(&[
+            "TITLE=So What",
+            "REPLAYGAIN_TRACK_GAIN=-6.48 dB",
+            "replaygain_track_peak=0.988",
+        ])).unwrap();
+        assert_eq!(read_replaygain(path).unwrap(), Some(ReplayGain {
+            track_gain: Some(-6.48),
+            track_peak: Some(0.988),
+        }));
+
+        write(path, flac_fixture(&["TITLE=So What"])).unwrap();
+        assert_eq!(read_replaygain(path).unwrap(), None);
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_replaygain_mp3() {
+        let path = Path::new("testfile_read_replaygain.mp3");
+        write(path, id3v2_fixture(&[
+            (b"TIT2", b"\x00So What".to_vec()),
+            (b"TXXX", b"\x00REPLAYGAIN_TRACK_GAIN\x00-6.48 dB".to_vec()),
+            (b"TXXX", b"\x03replaygain_track_peak\x000.988\x00".to_vec()),
+        ])).unwrap();
+        assert_eq!(read_replaygain(path).unwrap(), Some(ReplayGain {
+            track_gain: Some(-6.48),
+            track_peak: Some(0.988),
+        }));
+
+        write(path, id3v2_fixture(&[(b"TIT2", b"\x00So What".to_vec())])).unwrap();
+        assert_eq!(read_replaygain(path).unwrap(), None);
+
+        remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,189 @@
+//! # Vorbis Comments
+//!
+//! Reads the Vorbis comment header (`KEY=VALUE` tags) from OGG files.
+//!
+//! For a detailed explanation of the functions and their usage, see
+//! [the detailed explanation](../../explanations/tags.md).
+
+
+
+use std::io;
+use std::path::Path;
+use crate::file::{mmap_file, read_u32_le};
+
+
+
+
+// Parses a comment block: vendor string, then a count of length-prefixed KEY=VALUE entries
+pub(crate) fn parse_comments(data: &[u8]) -> Option<Vec<(String, String)>> {
+    let vendor_len = read_u32_le(data, 0)? as usize;
+    let mut pos = 4usize.checked_add(vendor_len)?;
+    let count = read_u32_le(data, pos)?;
+    pos += 4;
+
+    let mut comments = Vec::new();
+    for _ in 0..count {
+        let len = read_u32_le(data, pos)? as usize;
+        pos += 4;
+        let entry = data.get(pos..pos.checked_add(len)?)?;
+        pos += len;
+
+        let entry = String::from_utf8_lossy(entry);
+        if let Some((key, value)) = entry.split_once('=') {
+            comments.push((key.to_string(), value.to_string()));
+        }
+    }
+
+    Some(comments)
+}
+
+pub fn find_vorbis_comments(path: &Path) -> io::Result<Option<Vec<(String, String)>>> {
+    if std::fs::metadata(path)?.len() < 4 {
+        return Ok(None);
+    }
+    let (data, size) = unsafe { mmap_file(path)? };
+    if &data[..4] != b"OggS" {
+        return Ok(None);
+    }
+
+    let mut pos = 0;
+    let mut serial = None;
+    let mut packet = Vec::new();
+    let mut packet_index = 0;
+
+    while pos + 27 <= size {
+        if &data[pos..pos + 4] != b"OggS" {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad OGG page header."));
+        }
+        let page_serial = read_u32_le(&data, pos + 14);
+        let segments = data[pos + 26] as usize;
+        let table_end = pos + 27 + segments;
+        if table_end > size {
+            break;
+        }
+        let lacing = &data[pos + 27..table_end];
+        let body_len: usize = lacing.iter().map(|&l| l as usize).sum();
+        if table_end + body_len > size {
+            break;
+        }
+
+        // Only the first logical stream carries the headers we want
+        if serial.is_none() {
+            serial = page_serial;
+        }
+        if page_serial != serial {
+            pos = table_end + body_len;
+            continue;
+        }
+
+        let mut body = table_end;
+        for &len in lacing {
+            packet.extend_from_slice(&data[body..body + len as usize]);
+            body += len as usize;
+            if len == 255 {
+                continue; // Packet continues in the next segment
+            }
+
+            match packet_index {
+                0 if !packet.starts_with(b"\x01vorbis") => return Ok(None),
+                1 if !packet.starts_with(b"\x03vorbis") => return Ok(None),
+                1 => {
+                    return match parse_comments(&packet[7..]) {
+                        Some(comments) => Ok(Some(comments)),
+                        None => Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed Vorbis comment header.")),
+                    };
+                }
+                _ => {}
+            }
+            packet.clear();
+            packet_index += 1;
+        }
+
+        pos = table_end + body_len;
+    }
+
+    Ok(None)
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::fs::{remove_file, write};
+
+    pub(crate) fn comment_block(vendor: &str, comments: &[&str]) -> Vec<u8> {
+        let mut block = Vec::new();
+        block.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+        block.extend_from_slice(vendor.as_bytes());
+        block.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            block.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            block.extend_from_slice(comment.as_bytes());
+        }
+        block
+    }
+
+    fn ogg_page(sequence: u32, packet: &[u8]) -> Vec<u8> {
+        let mut lacing = vec![255u8; packet.len() / 255];
+        lacing.push((packet.len() % 255) as u8);
+
+        let mut page = Vec::new();
+        page.extend_from_slice(b"OggS");
+        page.push(0); // Version
+        page.push(if sequence == 0 { 2 } else { 0 }); // Beginning of stream
+        page.extend_from_slice(&0u64.to_le_bytes()); // Granule position
+        page.extend_from_slice(&1u32.to_le_bytes()); // Serial
+        page.extend_from_slice(&sequence.to_le_bytes());
+        page.extend_from_slice(&0u32.to_le_bytes()); // CRC, not checked by the reader
+        page.push(lacing.len() as u8);
+        page.extend_from_slice(&lacing);
+        page.extend_from_slice(packet);
+        page
+    }
+
+    pub(crate) fn ogg_fixture(comments: &[&str]) -> Vec<u8> {
+        let mut ident = b"\x01vorbis".to_vec();
+        ident.extend_from_slice(&[0u8; 23]);
+        let mut header = b"\x03vorbis".to_vec();
+        header.extend_from_slice(&comment_block("rust_cmus", comments));
+        header.push(1); // Framing bit
+
+        let mut data = ogg_page(0, &ident);
+        data.extend_from_slice(&ogg_page(1, &header));
+        data
+    }
+
+    #[test]
+    fn test_find_vorbis_comments() {
+        let path = Path::new("testfile_find_vorbis_comments.ogg");
+        write(path, ogg_fixture(&["TITLE=So What", "ARTIST=Miles Davis"])).unwrap();
+
+        let comments = find_vorbis_comments(path).unwrap().unwrap();
+        assert_eq!(comments, vec![
+            ("TITLE".to_string(), "So What".to_string()),
+            ("ARTIST".to_string(), "Miles Davis".to_string()),
+        ]);
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_find_vorbis_comments_long_packet() {
+        let path = Path::new("testfile_find_vorbis_comments_long.ogg");
+        let title = format!("TITLE={}", "x".repeat(600));
+        write(path, ogg_fixture(&[&title])).unwrap();
+
+        let comments = find_vorbis_comments(path).unwrap().unwrap();
+        assert_eq!(comments[0].1.len(), 600);
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_find_vorbis_comments_not_ogg() {
+        let path = Path::new("testfile_find_vorbis_comments_not_ogg.mp3");
+        write(path, b"ID3\x04\x00\x00\x00\x00\x00\x00").unwrap();
+        assert_eq!(find_vorbis_comments(path).unwrap(), None);
+        remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,60 @@
+//! # Timestamps
+//!
+//! Parses the `SS`, `MM:SS` and `HH:MM:SS` durations found in playlists.
+//!
+//! For a detailed explanation of the functions and their usage, see
+//! [the detailed explanation](../explanations/timestamp.md).
+
+
+
+use std::time::Duration;
+
+
+
+
+pub fn parse_timestamp(s: &str) -> Option<Duration> {
+    let mut fields = Vec::with_capacity(3);
+    for field in s.trim().split(':') {
+        // Only plain digits: parse() would also take a leading '+'
+        if field.is_empty() || !field.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        fields.push(field.parse::<u64>().ok()?);
+    }
+    if fields.len() > 3 {
+        return None;
+    }
+
+    // The leading field may be any size ("90", "75:00"), the others are 0-59
+    let mut seconds: u64 = 0;
+    for (i, &value) in fields.iter().enumerate() {
+        if i > 0 && value >= 60 {
+            return None;
+        }
+        seconds = seconds.checked_mul(60)?.checked_add(value)?;
+    }
+
+    Some(Duration::from_secs(seconds))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("3:45"), Some(Duration::from_secs(225)));
+        assert_eq!(parse_timestamp("1:02:03"), Some(Duration::from_secs(3723)));
+        assert_eq!(parse_timestamp("90"), Some(Duration::from_secs(90)));
+    }
+
+    #[test]
+    fn test_parse_timestamp_invalid() {
+        assert_eq!(parse_timestamp("x:y"), None);
+        assert_eq!(parse_timestamp("3:75"), None);
+        assert_eq!(parse_timestamp("1:2:3:4"), None);
+        assert_eq!(parse_timestamp("3:"), None);
+        assert_eq!(parse_timestamp(""), None);
+    }
+}
@@ -0,0 +1,413 @@
+//! # Directory Watching
+//!
+//! Reports audio files created, modified or removed under a library root.
+//!
+//! For a detailed explanation of the functions and their usage, see
+//! [the detailed explanation](../explanations/watch.md).
+
+
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::file::{file_token, scan_dir, FileToken};
+
+
+
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Created,
+    Modified,
+    Removed,
+}
+
+#[cfg(all(target_os = "linux", feature = "inotify"))]
+mod inotify {
+    use std::collections::HashMap;
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::io::{self, Read};
+    use std::os::fd::{AsRawFd, FromRawFd};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+    use super::{changes_between, snapshot, ChangeKind};
+    use crate::file::{file_token, has_extension, scan_dir, FileToken};
+
+    const MASK: u32 = libc::IN_CREATE | libc::IN_CLOSE_WRITE | libc::IN_DELETE | libc::IN_DELETE_SELF
+        | libc::IN_MOVED_FROM | libc::IN_MOVED_TO | libc::IN_ONLYDIR;
+    const EVENT_HEADER: usize = 16; // wd, mask, cookie, len
+
+    // Out of watches (fs.inotify.max_user_watches) or kernel memory; polling still works
+    pub(super) fn out_of_resources(e: &io::Error) -> bool {
+        matches!(e.raw_os_error(), Some(libc::ENOSPC) | Some(libc::ENOMEM))
+    }
+
+    pub(super) fn open() -> io::Result<File> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    // Like scan_dir, skip what we can't read, or what was deleted while we got to it
+    fn skippable(e: &io::Error) -> bool {
+        matches!(e.kind(), io::ErrorKind::PermissionDenied | io::ErrorKind::NotFound)
+            || e.raw_os_error() == Some(libc::ENOTDIR)
+    }
+
+    // Watches `dir` and every directory below it; inotify itself isn't recursive
+    fn add_tree(inotify: &File, dir: &Path, dirs: &mut HashMap<i32, PathBuf>) -> io::Result<()> {
+        let c_dir = CString::new(dir.as_os_str().as_bytes())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte"))?;
+        let wd = unsafe { libc::inotify_add_watch(inotify.as_raw_fd(), c_dir.as_ptr(), MASK) };
+        if wd < 0 {
+            let err = io::Error::last_os_error();
+            return if skippable(&err) { Ok(()) } else { Err(err) };
+        }
+        dirs.insert(wd, dir.to_path_buf());
+
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(ref e) if skippable(e) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(ref e) if skippable(e) => continue,
+                Err(e) => return Err(e),
+            };
+            if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+                add_tree(inotify, &entry.path(), dirs)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Stops watching `dir` and everything below it, which has left the tree
+    fn remove_tree(inotify: &File, dir: &Path, dirs: &mut HashMap<i32, PathBuf>) {
+        dirs.retain(|&wd, path| {
+            if !path.starts_with(dir) {
+                return true;
+            }
+            unsafe { libc::inotify_rm_watch(inotify.as_raw_fd(), wd) };
+            false
+        });
+    }
+
+    // The known files below a directory that was deleted or moved away, in path order
+    fn files_under(known: &HashMap<PathBuf, FileToken>, dir: &Path) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = known.keys().filter(|path| path.starts_with(dir)).cloned().collect();
+        files.sort();
+        files
+    }
+
+    // Keeps `known` up to date, so polling can take over from it if watches run out
+    pub(super) fn watch<F>(mut inotify: File, root: &Path, extensions: &[&str], known: &mut HashMap<PathBuf, FileToken>, on_ready: &mut dyn FnMut(), on_change: &mut F) -> io::Result<()>
+    where
+        F: FnMut(&Path, ChangeKind) -> bool,
+    {
+        let mut dirs = HashMap::new();
+        add_tree(&inotify, root, &mut dirs)?;
+        on_ready();
+        let mut buf = vec![0u8; 64 * 1024];
+
+        let mut report = |path: &Path, kind: ChangeKind, known: &mut HashMap<PathBuf, FileToken>| {
+            match file_token(path) {
+                Ok(token) if kind != ChangeKind::Removed => known.insert(path.to_path_buf(), token),
+                _ => known.remove(path),
+            };
+            on_change(path, kind)
+        };
+
+        loop {
+            let n = match inotify.read(&mut buf) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            let mut pos = 0;
+            while pos + EVENT_HEADER <= n {
+                let field = |at: usize| u32::from_ne_bytes(buf[pos + at..pos + at + 4].try_into().unwrap());
+                let wd = field(0) as i32;
+                let mask = field(4);
+                let len = field(12) as usize;
+                let name = &buf[pos + EVENT_HEADER..pos + EVENT_HEADER + len];
+                let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(len)];
+                pos += EVENT_HEADER + len;
+
+                if mask & libc::IN_Q_OVERFLOW != 0 {
+                    // Events were dropped: pick up any new directories and rescan for what was missed
+                    add_tree(&inotify, root, &mut dirs)?;
+                    let current = snapshot(root, extensions)?;
+                    for (path, kind) in changes_between(known, &current) {
+                        if report(&path, kind, known) {
+                            return Ok(());
+                        }
+                    }
+                    continue;
+                }
+                if mask & libc::IN_IGNORED != 0 {
+                    dirs.remove(&wd);
+                    continue;
+                }
+                let Some(dir) = dirs.get(&wd) else {
+                    continue;
+                };
+                let path = match mask & libc::IN_DELETE_SELF {
+                    0 => dir.join(std::ffi::OsStr::from_bytes(name)),
+                    _ => dir.clone(),
+                };
+
+                if mask & (libc::IN_ISDIR | libc::IN_DELETE_SELF) != 0 {
+                    if mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                        add_tree(&inotify, &path, &mut dirs)?;
+                        // Files may have landed before the watch was in place
+                        let files = match scan_dir(&path, extensions) {
+                            Ok(files) => files,
+                            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+                            Err(e) => return Err(e),
+                        };
+                        for file in files {
+                            if report(&file, ChangeKind::Created, known) {
+                                return Ok(());
+                            }
+                        }
+                    } else {
+                        // A directory deleted or moved away takes any files not yet reported gone with it
+                        remove_tree(&inotify, &path, &mut dirs);
+                        for file in files_under(known, &path) {
+                            if report(&file, ChangeKind::Removed, known) {
+                                return Ok(());
+                            }
+                        }
+                    }
+                    continue;
+                }
+                if !has_extension(&path, extensions) {
+                    continue;
+                }
+
+                let kind = if mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                    ChangeKind::Created
+                } else if mask & libc::IN_CLOSE_WRITE != 0 {
+                    ChangeKind::Modified
+                } else {
+                    ChangeKind::Removed
+                };
+                if report(&path, kind, known) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+fn snapshot(root: &Path, extensions: &[&str]) -> io::Result<HashMap<PathBuf, FileToken>> {
+    let mut files = HashMap::new();
+    for path in scan_dir(root, extensions)? {
+        // A file removed since the scan just doesn't make it into this snapshot
+        if let Ok(token) = file_token(&path) {
+            files.insert(path, token);
+        }
+    }
+    Ok(files)
+}
+
+// What differs between two snapshots, in path order
+fn changes_between(known: &HashMap<PathBuf, FileToken>, current: &HashMap<PathBuf, FileToken>) -> Vec<(PathBuf, ChangeKind)> {
+    let mut changes = Vec::new();
+    for (path, token) in current {
+        match known.get(path) {
+            None => changes.push((path.clone(), ChangeKind::Created)),
+            Some(old) if old != token => changes.push((path.clone(), ChangeKind::Modified)),
+            Some(_) => {}
+        }
+    }
+    changes.extend(known.keys().filter(|path| !current.contains_key(*path)).map(|path| (path.clone(), ChangeKind::Removed)));
+    changes.sort_by(|a, b| a.0.cmp(&b.0));
+    changes
+}
+
+fn watch_poll<F>(root: &Path, extensions: &[&str], interval: Duration, mut known: HashMap<PathBuf, FileToken>, on_change: &mut F) -> io::Result<()>
+where
+    F: FnMut(&Path, ChangeKind) -> bool,
+{
+    loop {
+        std::thread::sleep(interval);
+        let current = snapshot(root, extensions)?;
+
+        for (path, kind) in changes_between(&known, &current) {
+            if on_change(&path, kind) {
+                return Ok(());
+            }
+        }
+        known = current;
+    }
+}
+
+pub fn watch_dir<F>(root: &Path, extensions: &[&str], on_change: F) -> io::Result<()>
+where
+    F: FnMut(&Path, ChangeKind) -> bool,
+{
+    watch_dir_with(root, extensions, || {}, on_change)
+}
+
+// watch_dir with a hook called once changes are being watched for, so tests can wait for it
+fn watch_dir_with<R, F>(root: &Path, extensions: &[&str], on_ready: R, mut on_change: F) -> io::Result<()>
+where
+    R: FnOnce(),
+    F: FnMut(&Path, ChangeKind) -> bool,
+{
+    let mut on_ready = Some(on_ready);
+    let mut ready = move || {
+        if let Some(on_ready) = on_ready.take() {
+            on_ready();
+        }
+    };
+    let known = snapshot(root, extensions)?;
+
+    #[cfg(all(target_os = "linux", feature = "inotify"))]
+    let known = match inotify::open() {
+        Ok(file) => {
+            let mut known = known;
+            match inotify::watch(file, root, extensions, &mut known, &mut ready, &mut on_change) {
+                // Polling carries on from the files inotify has already reported
+                Err(ref e) if inotify::out_of_resources(e) => known,
+                result => return result,
+            }
+        }
+        Err(_) => known,
+    };
+
+    // No native watcher (or it's out of instances or watches): compare snapshots instead
+    ready();
+    watch_poll(root, extensions, POLL_INTERVAL, known, &mut on_change)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+    use std::sync::mpsc;
+
+    // Fails instead of hanging if the watcher never reports anything
+    const DEADLINE: Duration = Duration::from_secs(10);
+
+    fn first_change<W>(dir: &Path, watch: W) -> (PathBuf, ChangeKind)
+    where
+        W: FnOnce(&Path, Box<dyn FnOnce() + Send>, &mut dyn FnMut(&Path, ChangeKind) -> bool) -> io::Result<()> + Send + 'static,
+    {
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let (tx, rx) = mpsc::channel();
+        let root = dir.to_path_buf();
+        std::thread::spawn(move || {
+            let on_ready = Box::new(move || ready_tx.send(()).unwrap());
+            watch(&root, on_ready, &mut |path, kind| {
+                let _ = tx.send((path.to_path_buf(), kind));
+                true
+            }).unwrap();
+        });
+
+        // Writing before the watches are in place would race the watcher
+        ready_rx.recv_timeout(DEADLINE).expect("watcher not ready before the deadline");
+        write(dir.join("album/notes.txt"), b"not audio").unwrap();
+        write(dir.join("album/new.flac"), b"fLaC").unwrap();
+        rx.recv_timeout(DEADLINE).expect("no change reported before the deadline")
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "inotify"))]
+    fn test_watch_dir() {
+        let dir = Path::new("testdir_watch_dir");
+        create_dir_all(dir.join("album")).unwrap();
+
+        let seen = first_change(dir, |root, on_ready, on_change| watch_dir_with(root, &["flac"], on_ready, on_change));
+        assert_eq!(seen, (dir.join("album/new.flac"), ChangeKind::Created));
+
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", feature = "inotify"))]
+    fn test_watch_dir_removed_directory() {
+        let dir = Path::new("testdir_watch_dir_removed_directory");
+        create_dir_all(dir.join("album/disc1")).unwrap();
+        write(dir.join("album/disc1/a.flac"), b"fLaC").unwrap();
+        write(dir.join("album/b.flac"), b"fLaC").unwrap();
+
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let (tx, rx) = mpsc::channel();
+        let root = dir.to_path_buf();
+        std::thread::spawn(move || {
+            watch_dir_with(&root, &["flac"], move || ready_tx.send(()).unwrap(), |path, kind| {
+                tx.send((path.to_path_buf(), kind)).is_err()
+            }).unwrap();
+        });
+        ready_rx.recv_timeout(DEADLINE).expect("watcher not ready before the deadline");
+
+        // Moving the directory out of the tree removes its files, with no event per file
+        std::fs::rename(dir.join("album"), "testdir_watch_dir_removed_directory_moved").unwrap();
+        let seen = vec![rx.recv_timeout(DEADLINE).unwrap(), rx.recv_timeout(DEADLINE).unwrap()];
+        assert_eq!(seen, vec![
+            (dir.join("album/b.flac"), ChangeKind::Removed),
+            (dir.join("album/disc1/a.flac"), ChangeKind::Removed),
+        ]);
+
+        // A directory that is gone again before it can be watched or scanned is skipped
+        for _ in 0..20 {
+            create_dir_all(dir.join("flash/deep")).unwrap();
+            remove_dir_all(dir.join("flash")).unwrap();
+        }
+        write(dir.join("after.flac"), b"fLaC").unwrap();
+        assert_eq!(rx.recv_timeout(DEADLINE).unwrap(), (dir.join("after.flac"), ChangeKind::Created));
+
+        remove_dir_all("testdir_watch_dir_removed_directory_moved").unwrap();
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_watch_poll() {
+        let dir = Path::new("testdir_watch_poll");
+        create_dir_all(dir.join("album")).unwrap();
+
+        let seen = first_change(dir, |root, on_ready, mut on_change| {
+            let known = snapshot(root, &["flac"])?;
+            on_ready();
+            watch_poll(root, &["flac"], Duration::from_millis(20), known, &mut on_change)
+        });
+        assert_eq!(seen, (dir.join("album/new.flac"), ChangeKind::Created));
+
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_changes_between() {
+        let dir = Path::new("testdir_changes_between");
+        create_dir_all(dir).unwrap();
+        for name in ["kept.flac", "changed.flac", "removed.flac"] {
+            write(dir.join(name), b"fLaC").unwrap();
+        }
+        let known = snapshot(dir, &["flac"]).unwrap();
+
+        std::fs::remove_file(dir.join("removed.flac")).unwrap();
+        write(dir.join("changed.flac"), b"fLaC and more").unwrap();
+        write(dir.join("added.flac"), b"fLaC").unwrap();
+        let current = snapshot(dir, &["flac"]).unwrap();
+
+        // This is also what polling reports when it takes over from inotify with these files known
+        assert_eq!(changes_between(&known, &current), vec![
+            (dir.join("added.flac"), ChangeKind::Created),
+            (dir.join("changed.flac"), ChangeKind::Modified),
+            (dir.join("removed.flac"), ChangeKind::Removed),
+        ]);
+
+        remove_dir_all(dir).unwrap();
+    }
+}